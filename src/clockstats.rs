@@ -0,0 +1,234 @@
+/**
+ *  Terminal MIDI Monitor -- Shows MIDI Events on the terminal
+ *  Copyright (C) 2019 David Moreno / Coralbits SL <dmoreno@coralbits.com>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+// An interval this much longer than expected means clocks were lost, this much shorter a doubled clock.
+const MISSING_CLOCK_RATIO: f64 = 1.5;
+const DOUBLED_CLOCK_RATIO: f64 = 0.5;
+
+const CLOCKS_PER_BEAT: f64 = 24.0;
+
+/// Running min/max/mean/stddev, using Welford's algorithm so it does not drift on long runs.
+#[derive(Default)]
+pub struct IntervalStats {
+    count: u64,
+    mean: f64,
+    m2: f64,
+    min: f64,
+    max: f64,
+}
+
+impl IntervalStats {
+    fn add(&mut self, interval: f64) {
+        if self.count == 0 || interval < self.min {
+            self.min = interval;
+        }
+        if self.count == 0 || interval > self.max {
+            self.max = interval;
+        }
+        self.count += 1;
+        let delta = interval - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (interval - self.mean);
+    }
+
+    fn stddev(&self) -> f64 {
+        if self.count < 2 {
+            0.0
+        } else {
+            (self.m2 / (self.count - 1) as f64).sqrt()
+        }
+    }
+
+    fn to_ms_string(&self) -> String {
+        if self.count == 0 {
+            return "no intervals".to_string();
+        }
+        format!(
+            "interval {:.3} ms (min {:.3}, max {:.3}, stddev {:.3})",
+            self.mean * 1000.0, self.min * 1000.0, self.max * 1000.0, self.stddev() * 1000.0
+        )
+    }
+}
+
+/// Analysis of the interval between clocks, for qualifying clock sources.
+pub struct ClockStats {
+    nominal_bpm: Option<f64>,
+    last_clock: Option<f64>,
+    first_clock: Option<f64>,
+    clocks: u64, // Since first_clock, counting the missing ones
+    beat: IntervalStats,
+    total: IntervalStats,
+    missing: u64,
+    doubled: u64,
+}
+
+impl ClockStats {
+    pub fn new(nominal_bpm: Option<f64>) -> ClockStats {
+        ClockStats {
+            nominal_bpm,
+            last_clock: None,
+            first_clock: None,
+            clocks: 0,
+            beat: IntervalStats::default(),
+            total: IntervalStats::default(),
+            missing: 0,
+            doubled: 0,
+        }
+    }
+
+    /// Transport changed (Start, Stop, Continue, Song Position). The interval up to the
+    /// next clock means nothing, and drift starts to count again.
+    pub fn restart(&mut self) {
+        self.last_clock = None;
+        self.first_clock = None;
+        self.clocks = 0;
+    }
+
+    fn nominal_sec_per_clock(&self) -> Option<f64> {
+        self.nominal_bpm.map(|bpm| 60.0 / (bpm * CLOCKS_PER_BEAT))
+    }
+
+    /// New clock at `now` seconds. `average_sec_per_clock` is used to find missing and
+    /// doubled clocks when there is no nominal BPM.
+    pub fn clock(&mut self, now: f64, average_sec_per_clock: f64) {
+        let last_clock = self.last_clock.replace(now);
+        if self.first_clock.is_none() {
+            self.first_clock = Some(now);
+        }
+        let last_clock = match last_clock {
+            Some(last_clock) => last_clock,
+            None => return,
+        };
+        let interval = now - last_clock;
+        self.beat.add(interval);
+        self.total.add(interval);
+
+        let expected = self.nominal_sec_per_clock().unwrap_or(average_sec_per_clock);
+        if interval > expected * MISSING_CLOCK_RATIO {
+            let missing = (interval / expected).round() as u64 - 1;
+            self.missing += missing;
+            self.clocks += missing;
+        } else if interval < expected * DOUBLED_CLOCK_RATIO {
+            self.doubled += 1;
+        }
+        self.clocks += 1;
+    }
+
+    /// Time the clock is ahead (negative) or behind (positive) of the nominal BPM, in seconds.
+    pub fn drift(&self) -> Option<f64> {
+        let sec_per_clock = self.nominal_sec_per_clock()?;
+        let first_clock = self.first_clock?;
+        let last_clock = self.last_clock?;
+        Some((last_clock - first_clock) - self.clocks as f64 * sec_per_clock)
+    }
+
+    fn drift_to_string(&self) -> String {
+        match (self.drift(), self.first_clock, self.last_clock) {
+            (Some(drift), Some(first_clock), Some(last_clock)) if last_clock > first_clock => {
+                let ppm = drift / (last_clock - first_clock) * 1_000_000.0;
+                format!(" | drift {:+.3} ms ({:+.0} ppm)", drift * 1000.0, ppm)
+            }
+            _ => "".to_string(),
+        }
+    }
+
+    /// Stats of the last beat. Starts a new beat.
+    pub fn beat_summary(&mut self) -> String {
+        let summary = format!(
+            "{} | missing {} | doubled {}{}",
+            self.beat.to_ms_string(), self.missing, self.doubled, self.drift_to_string()
+        );
+        self.beat = IntervalStats::default();
+        summary
+    }
+
+    pub fn has_data(&self) -> bool {
+        self.total.count > 0
+    }
+
+    /// Stats of the whole run.
+    pub fn summary(&self) -> String {
+        let bpm = if self.total.mean > 0.0 {
+            60.0 / (self.total.mean * CLOCKS_PER_BEAT)
+        } else {
+            0.0
+        };
+        format!(
+            "{} intervals | {:.2} BPM | {} | missing {} | doubled {}{}",
+            self.total.count, bpm, self.total.to_ms_string(), self.missing, self.doubled, self.drift_to_string()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEC_PER_CLOCK: f64 = 60.0 / (120.0 * CLOCKS_PER_BEAT);
+
+    fn feed(stats: &mut ClockStats, times: &[f64]) {
+        for time in times {
+            stats.clock(*time, SEC_PER_CLOCK);
+        }
+    }
+
+    fn steady(clocks: usize, sec_per_clock: f64) -> Vec<f64> {
+        (0..clocks).map(|clock| clock as f64 * sec_per_clock).collect()
+    }
+
+    #[test]
+    fn steady_clock() {
+        let mut stats = ClockStats::new(Some(120.0));
+        feed(&mut stats, &steady(25, SEC_PER_CLOCK));
+        assert_eq!(stats.total.count, 24);
+        assert_eq!((stats.missing, stats.doubled), (0, 0));
+        assert!((stats.total.mean - SEC_PER_CLOCK).abs() < 1e-9);
+        assert!(stats.total.stddev() < 1e-9);
+        assert!(stats.drift().unwrap().abs() < 1e-9);
+    }
+
+    #[test]
+    fn one_clock_missing() {
+        let mut stats = ClockStats::new(Some(120.0));
+        let mut times = steady(25, SEC_PER_CLOCK);
+        times.remove(10);
+        feed(&mut stats, &times);
+        assert_eq!((stats.missing, stats.doubled), (1, 0));
+        // The missing clock still counts, so there is no drift.
+        assert!(stats.drift().unwrap().abs() < 1e-9);
+    }
+
+    #[test]
+    fn one_clock_doubled() {
+        let mut stats = ClockStats::new(None);
+        let mut times = steady(25, SEC_PER_CLOCK);
+        times.insert(11, times[10] + SEC_PER_CLOCK * 0.2);
+        feed(&mut stats, &times);
+        assert_eq!((stats.missing, stats.doubled), (0, 1));
+        assert!(stats.drift().is_none());
+    }
+
+    #[test]
+    fn slow_clock_drifts_behind() {
+        let mut stats = ClockStats::new(Some(120.0));
+        feed(&mut stats, &steady(97, SEC_PER_CLOCK * 1.01));
+        assert_eq!((stats.missing, stats.doubled), (0, 0));
+        let drift = stats.drift().unwrap();
+        assert!((drift - 96.0 * SEC_PER_CLOCK * 0.01).abs() < 1e-9);
+        assert!(stats.summary().contains("drift +20.000 ms"));
+    }
+}
@@ -0,0 +1,91 @@
+/**
+ *  Terminal MIDI Monitor -- Shows MIDI Events on the terminal
+ *  Copyright (C) 2019 David Moreno / Coralbits SL <dmoreno@coralbits.com>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+// CC 0-31 are the MSB, and CC 32-63 the LSB of the same controllers.
+const LSB_OFFSET: u32 = 32;
+const CONTROLLERS: usize = 32;
+
+/// What a controller change meant for the 14 bit controller state of its channel.
+pub enum Control14Update {
+    /// Not a 14 bit capable controller.
+    None,
+    /// New combined value for the controller (0-31).
+    Value(u32, u16),
+}
+
+/// Last MSB per controller for one channel of one source.
+///
+/// A MSB shows right away as MSB << 7, as the MIDI spec says a new MSB resets the LSB,
+/// and the LSB refines it when it arrives. Many devices send the LSB only when it
+/// changes, so waiting for it would hide coarse moves.
+#[derive(Default)]
+pub struct Control14State {
+    msb: [Option<u8>; CONTROLLERS],
+}
+
+impl Control14State {
+    pub fn update(&mut self, param: u32, value: i32) -> Control14Update {
+        let value = (value & 0x7F) as u8;
+        if param < LSB_OFFSET {
+            let controller = param as usize;
+            self.msb[controller] = Some(value);
+            return Control14Update::Value(param, (value as u16) << 7);
+        }
+        if param < LSB_OFFSET * 2 {
+            let controller = (param - LSB_OFFSET) as usize;
+            if let Some(msb) = self.msb[controller] {
+                return Control14Update::Value(param - LSB_OFFSET, (msb as u16) << 7 | value as u16);
+            }
+        }
+        Control14Update::None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(update: Control14Update) -> Option<(u32, u16)> {
+        match update {
+            Control14Update::Value(param, value) => Some((param, value)),
+            Control14Update::None => None,
+        }
+    }
+
+    #[test]
+    fn msb_then_lsb() {
+        let mut state = Control14State::default();
+        assert_eq!(value(state.update(7, 0x40)), Some((7, 0x40 << 7)));
+        assert_eq!(value(state.update(39, 0x05)), Some((7, 0x40 << 7 | 0x05)));
+    }
+
+    #[test]
+    fn msb_shows_even_after_lsb_was_seen() {
+        let mut state = Control14State::default();
+        state.update(1, 0x10);
+        state.update(33, 0x7F);
+        // A new MSB resets the LSB to 0 until the device sends it again
+        assert_eq!(value(state.update(1, 0x11)), Some((1, 0x11 << 7)));
+    }
+
+    #[test]
+    fn lsb_without_msb_and_other_controllers() {
+        let mut state = Control14State::default();
+        assert_eq!(value(state.update(40, 0x01)), None);
+        assert_eq!(value(state.update(64, 0x7F)), None);
+    }
+}
@@ -0,0 +1,197 @@
+/**
+ *  Terminal MIDI Monitor -- Shows MIDI Events on the terminal
+ *  Copyright (C) 2019 David Moreno / Coralbits SL <dmoreno@coralbits.com>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use colored::*;
+use std::collections::BTreeMap;
+use crate::event::{MidiEvent, CC_ALL_NOTES_OFF, CC_ALL_SOUND_OFF, CC_RESET_ALL_CONTROLLERS};
+use crate::mpe;
+use crate::rpn;
+use crate::{note_name, CC_MAP, PROGRAM_MAP};
+
+/// Current state of a channel, as the receiving synth would have it.
+#[derive(Clone, Default)]
+struct ChannelState {
+    program: Option<i32>,
+    pitchbend: Option<i32>,
+    pressure: Option<i32>,
+    controllers: BTreeMap<(u32, bool), i32>, // By number and whether 14 bit
+    parameters: BTreeMap<(bool, u8, u8), rpn::Parameter>, // By registered, MSB and LSB
+    notes: BTreeMap<u8, u8>, // Held notes and their velocity
+    expression: Option<mpe::NoteExpression>, // Last MPE expression, in MPE mode
+}
+
+impl ChannelState {
+    fn is_empty(&self) -> bool {
+        self.program.is_none()
+            && self.pitchbend.is_none()
+            && self.pressure.is_none()
+            && self.controllers.is_empty()
+            && self.parameters.is_empty()
+            && self.notes.is_empty()
+            && self.expression.is_none()
+    }
+
+    // Each item is plain text, so the lines can be wrapped by length.
+    fn items(&self) -> Vec<String> {
+        let mut items = Vec::new();
+        if let Some(program) = self.program {
+            items.push(format!(
+                "Program {} {}",
+                program,
+                PROGRAM_MAP.get(&(program as u32)).map(|name| name.as_str()).unwrap_or("Unknown")
+            ));
+        }
+        if let Some(pitchbend) = self.pitchbend {
+            items.push(format!("Pitch Bend {:+}", pitchbend));
+        }
+        if let Some(pressure) = self.pressure {
+            items.push(format!("Pressure {}", pressure));
+        }
+        if let Some(expression) = self.expression.as_ref() {
+            items.push(format!("MPE {}", expression));
+        }
+        let notes: Vec<String> = self.notes.iter()
+            .map(|(note, velocity)| format!("{}({})", note_name(*note), velocity))
+            .collect();
+        items.push(if notes.is_empty() { "No notes".to_string() } else { format!("Notes {}", notes.join(" ")) });
+        for ((param, is14), value) in &self.controllers {
+            items.push(format!(
+                "{} {} {} = {}",
+                if *is14 { "CC14" } else { "CC" },
+                param,
+                CC_MAP.get(param).map(|name| name.as_str()).unwrap_or("Unknown"),
+                value
+            ));
+        }
+        for parameter in self.parameters.values() {
+            items.push(parameter.to_string());
+        }
+        items
+    }
+}
+
+/// Per channel state of controllers, pitch bend, pressure, program and held notes.
+pub struct Dashboard {
+    channels: Vec<ChannelState>,
+}
+
+impl Default for Dashboard {
+    fn default() -> Dashboard {
+        Dashboard { channels: vec![ChannelState::default(); 16] }
+    }
+}
+
+impl Dashboard {
+    pub fn update(&mut self, event: &MidiEvent) {
+        let channel = match event.channel().and_then(|channel| self.channels.get_mut(channel as usize)) {
+            Some(channel) => channel,
+            None => return,
+        };
+        match event {
+            MidiEvent::NoteOn { note, velocity, .. } if *velocity > 0 => {
+                channel.notes.insert(*note, *velocity);
+            }
+            MidiEvent::NoteOn { note, .. } | MidiEvent::NoteOff { note, .. } => {
+                channel.notes.remove(note);
+            }
+            MidiEvent::Controller { param, value, .. } | MidiEvent::Controller14 { param, value, .. } => {
+                let is14 = matches!(event, MidiEvent::Controller14 { .. });
+                match *param {
+                    CC_ALL_SOUND_OFF | CC_ALL_NOTES_OFF => channel.notes.clear(),
+                    CC_RESET_ALL_CONTROLLERS => {
+                        channel.controllers.clear();
+                        channel.pitchbend = None;
+                        channel.pressure = None;
+                        return;
+                    }
+                    _ => {}
+                }
+                channel.controllers.insert((*param, is14), *value);
+            }
+            MidiEvent::Parameter { parameter, .. } => {
+                let registered = parameter.kind == rpn::ParameterKind::Registered;
+                channel.parameters.insert((registered, parameter.msb, parameter.lsb), parameter.clone());
+            }
+            MidiEvent::PitchBend { value, .. } => channel.pitchbend = Some(*value),
+            MidiEvent::ChannelPressure { value, .. } => channel.pressure = Some(*value),
+            MidiEvent::ProgramChange { program, .. } => channel.program = Some(*program),
+            MidiEvent::MpeExpression { expression, .. } => channel.expression = Some(expression.clone()),
+            _ => {}
+        }
+    }
+
+    /// The dashboard as lines of at most `width` characters. Only channels with some state.
+    pub fn lines(&self, width: usize) -> Vec<String> {
+        let mut lines = Vec::new();
+        for (number, channel) in self.channels.iter().enumerate() {
+            if channel.is_empty() {
+                continue;
+            }
+            let header = format!("Channel {:2}", number);
+            let indent = header.len() + 3;
+            let mut line = header.bold().to_string();
+            let mut line_len = header.len();
+            for item in channel.items() {
+                if line_len + 3 + item.len() > width && line_len > indent {
+                    lines.push(line);
+                    line = " ".repeat(indent - 3);
+                    line_len = indent - 3;
+                }
+                line.push_str(" | ");
+                line.push_str(&item);
+                line_len += 3 + item.len();
+            }
+            lines.push(line);
+        }
+        if lines.is_empty() {
+            lines.push("No channel events yet".to_string());
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plain(dashboard: &Dashboard) -> String {
+        colored::control::set_override(false);
+        dashboard.lines(200).join("\n")
+    }
+
+    #[test]
+    fn controllers_7_and_14_bit_apart() {
+        let mut dashboard = Dashboard::default();
+        dashboard.update(&MidiEvent::Controller14 { channel: 0, param: 7, value: 0x2000 });
+        dashboard.update(&MidiEvent::Controller { channel: 0, param: 7, value: 90 });
+        let lines = plain(&dashboard);
+        assert!(lines.contains("CC14 7 Volume = 8192"), "{}", lines);
+        assert!(lines.contains("CC 7 Volume = 90"), "{}", lines);
+    }
+
+    #[test]
+    fn notes_and_reset() {
+        let mut dashboard = Dashboard::default();
+        dashboard.update(&MidiEvent::NoteOn { channel: 1, note: 60, velocity: 100 });
+        dashboard.update(&MidiEvent::PitchBend { channel: 1, value: 100 });
+        assert!(plain(&dashboard).contains("Notes C5(100)"));
+        dashboard.update(&MidiEvent::Controller { channel: 1, param: CC_ALL_NOTES_OFF, value: 0 });
+        dashboard.update(&MidiEvent::Controller { channel: 1, param: CC_RESET_ALL_CONTROLLERS, value: 0 });
+        // Nothing left, so the channel is not shown
+        assert_eq!(plain(&dashboard), "No channel events yet");
+    }
+}
@@ -0,0 +1,277 @@
+/**
+ *  Terminal MIDI Monitor -- Shows MIDI Events on the terminal
+ *  Copyright (C) 2019 David Moreno / Coralbits SL <dmoreno@coralbits.com>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use alsa::seq;
+use std::error;
+use crate::control14;
+use crate::event::{DecodedEvent, MidiEvent};
+use crate::mpe;
+use crate::mtc;
+use crate::ports;
+use crate::rpn;
+use crate::sysex;
+use crate::{MidiMonitor, BPM_DAMPING, CLOCKS_PER_SONG_POSITION};
+
+/// A SysEx still waiting for its F7 when a new one starts from the same source.
+pub fn truncated_sysex(midi_monitor: &mut MidiMonitor, ev: &seq::Event, elapsed: f64) -> Result<Option<DecodedEvent>, Box<dyn error::Error>> {
+    if ev.get_type() != seq::EventType::Sysex || ev.get_ext().and_then(|data| data.first()) != Some(&sysex::SYSEX_START) {
+        return Ok(None);
+    }
+    let source = ev.get_source();
+    let data = match midi_monitor.sysex_buffers.remove(&source) {
+        Some(data) => data,
+        None => return Ok(None),
+    };
+    let origin = midi_monitor.get_origin(ev)?;
+    Ok(Some(DecodedEvent { time: elapsed, processed: None, source, origin, event: MidiEvent::SysExTruncated { data } }))
+}
+
+/// Decodes an ALSA event, updating the monitor state on the way.
+///
+/// Returns None for events that are only part of a bigger one (sysex parts, MTC quarter
+/// frames, RPN selection...), which show once complete. `elapsed` is the event time in seconds.
+pub fn decode_midi_ev(midi_monitor: &mut MidiMonitor, ev: &seq::Event, elapsed: f64) -> Result<Option<DecodedEvent>, Box<dyn error::Error>> {
+    let origin = midi_monitor.get_origin(ev)?;
+    let source = ev.get_source();
+
+    let event = match ev.get_type() {
+        seq::EventType::Noteon => {
+            let data: seq::EvNote = ev.get_data().ok_or("Error resolving event data")?;
+            if let Some(mpe_state) = midi_monitor.mpe_state(source) {
+                if data.velocity > 0 {
+                    mpe_state.note_on(data.channel, data.note);
+                } else {
+                    mpe_state.note_off(data.channel, data.note);
+                }
+            }
+            MidiEvent::NoteOn { channel: data.channel, note: data.note, velocity: data.velocity }
+        },
+        seq::EventType::Noteoff => {
+            let data: seq::EvNote = ev.get_data().ok_or("Error resolving event data")?;
+            if let Some(mpe_state) = midi_monitor.mpe_state(source) {
+                mpe_state.note_off(data.channel, data.note);
+            }
+            MidiEvent::NoteOff { channel: data.channel, note: data.note, velocity: data.velocity }
+        },
+        seq::EventType::Keypress => {
+            let data: seq::EvNote = ev.get_data().ok_or("Error resolving event data")?;
+            MidiEvent::PolyAftertouch { channel: data.channel, note: data.note, pressure: data.velocity }
+        },
+        seq::EventType::Controller => {
+            let data: seq::EvCtrl = ev.get_data().ok_or("Error resolving event data")?;
+            match decode_controller(midi_monitor, source, data) {
+                Some(event) => event,
+                None => return Ok(None),
+            }
+        },
+        seq::EventType::Control14 => {
+            let data: seq::EvCtrl = ev.get_data().ok_or("Error resolving event data")?;
+            MidiEvent::Controller14 { channel: data.channel, param: data.param, value: data.value }
+        },
+        seq::EventType::Regparam | seq::EventType::Nonregparam => {
+            let data: seq::EvCtrl = ev.get_data().ok_or("Error resolving event data")?;
+            let kind = if ev.get_type() == seq::EventType::Regparam {
+                rpn::ParameterKind::Registered
+            } else {
+                rpn::ParameterKind::NonRegistered
+            };
+            let parameter = rpn::Parameter::from_14bit(kind, data.param, data.value);
+            let mpe_zones = midi_monitor.apply_parameter(source, data.channel, &parameter);
+            MidiEvent::Parameter { channel: data.channel, parameter, mpe_zones }
+        },
+        seq::EventType::Pitchbend => {
+            let data: seq::EvCtrl = ev.get_data().ok_or("Error resolving event data")?;
+            match midi_monitor.mpe_state(source).and_then(|mpe_state| mpe_state.pitchbend(data.channel, data.value)) {
+                Some(expression) => MidiEvent::MpeExpression { channel: data.channel, expression },
+                None => MidiEvent::PitchBend { channel: data.channel, value: data.value },
+            }
+        },
+        seq::EventType::Pgmchange => {
+            let data: seq::EvCtrl = ev.get_data().ok_or("Error resolving event data")?;
+            MidiEvent::ProgramChange { channel: data.channel, program: data.value }
+        },
+        seq::EventType::Chanpress => {
+            let data: seq::EvCtrl = ev.get_data().ok_or("Error resolving event data")?;
+            match midi_monitor.mpe_state(source).and_then(|mpe_state| mpe_state.pressure(data.channel, data.value)) {
+                Some(expression) => MidiEvent::MpeExpression { channel: data.channel, expression },
+                None => MidiEvent::ChannelPressure { channel: data.channel, value: data.value },
+            }
+        }
+        seq::EventType::Sysex => {
+            let data = ev.get_ext().ok_or("Error resolving event data")?;
+            let mut message = match midi_monitor.sysex_buffers.remove(&source) {
+                Some(buffer) if data.first() != Some(&sysex::SYSEX_START) => buffer,
+                _ => Vec::new(),
+            };
+            message.extend_from_slice(data);
+            if message.first() == Some(&sysex::SYSEX_START) && message.last() != Some(&sysex::SYSEX_END) {
+                // Wait for the rest of the message
+                midi_monitor.sysex_buffers.insert(source, message);
+                return Ok(None);
+            }
+            match midi_monitor.mtc.full_frame(&message) {
+                Some(mtc::MtcUpdate::Located(timecode)) => MidiEvent::MtcFullFrame { timecode },
+                _ => MidiEvent::SysEx { data: message },
+            }
+        }
+        seq::EventType::Qframe => {
+            let data: seq::EvCtrl = ev.get_data().ok_or("Error resolving event data")?;
+            match midi_monitor.mtc.quarter_frame(data.value) {
+                mtc::MtcUpdate::Running(timecode) | mtc::MtcUpdate::Located(timecode) => MidiEvent::Mtc { timecode },
+                mtc::MtcUpdate::Dropped(timecode, frames, quarter_frames) => MidiEvent::MtcDropped { timecode, frames, quarter_frames },
+                mtc::MtcUpdate::Backwards(timecode) => MidiEvent::MtcBackwards { timecode },
+                mtc::MtcUpdate::Partial => return Ok(None),
+            }
+        }
+        seq::EventType::Clock => {
+            midi_monitor.clock_pos += 1;
+            midi_monitor.average_sec_per_clock =
+                ((elapsed - midi_monitor.last_clock) * BPM_DAMPING) +
+                midi_monitor.average_sec_per_clock * (1.0 - BPM_DAMPING);
+            midi_monitor.last_clock = elapsed;
+            if let Some(clock_stats) = midi_monitor.clock_stats.as_mut() {
+                clock_stats.clock(elapsed, midi_monitor.average_sec_per_clock);
+            }
+
+            // I hope RUST simplifies this.. as I prefer clean code.
+            let cs = 1.0 / midi_monitor.average_sec_per_clock;
+            let bs = cs / 24.0; // 24 clocks per beat -> beats per second
+            let bpm = bs * 60.0;
+
+            // clock_pos is the position of the next clock, so this one is at clock_pos - 1.
+            let position = midi_monitor.clock_pos - 1;
+            let beat = position % midi_monitor.clocks_per_beat() == 0;
+            let bars_beats_ticks = midi_monitor.bars_beats_ticks(position);
+            let stats = match midi_monitor.clock_stats.as_mut() {
+                Some(clock_stats) if beat => Some(clock_stats.beat_summary()),
+                _ => None,
+            };
+            MidiEvent::Clock { bpm, clock_pos: midi_monitor.clock_pos, bars_beats_ticks, beat, stats }
+        }
+        seq::EventType::Songpos => {
+            let data: seq::EvCtrl = ev.get_data().ok_or("Error resolving event data")?;
+            midi_monitor.clock_pos = data.value * CLOCKS_PER_SONG_POSITION;
+            midi_monitor.restart_clock_stats();
+            MidiEvent::SongPosition {
+                value: data.value,
+                bars_beats_ticks: midi_monitor.bars_beats_ticks(midi_monitor.clock_pos),
+            }
+        }
+        seq::EventType::Songsel => {
+            let data: seq::EvCtrl = ev.get_data().ok_or("Error resolving event data")?;
+            MidiEvent::SongSelect { song: data.value }
+        }
+        seq::EventType::Start => {
+            midi_monitor.clock_pos = 0;
+            midi_monitor.restart_clock_stats();
+            MidiEvent::Start
+        }
+        seq::EventType::Stop => {
+            midi_monitor.restart_clock_stats();
+            MidiEvent::Stop
+        }
+        seq::EventType::Continue => {
+            midi_monitor.restart_clock_stats();
+            MidiEvent::Continue
+        }
+        seq::EventType::TuneRequest => MidiEvent::TuneRequest,
+        seq::EventType::Reset => MidiEvent::Reset,
+        seq::EventType::Sensing => MidiEvent::Sensing,
+        seq::EventType::ClientStart => {
+            let addr: seq::Addr = ev.get_data().ok_or("Expected address")?;
+            MidiEvent::ClientStart { name: midi_monitor.get_port_name(addr)? }
+        }
+        seq::EventType::PortStart => {
+            let addr: seq::Addr = ev.get_data().ok_or("Expected address")?;
+            if midi_monitor.wants_port(addr) {
+                midi_monitor.connect_from(addr)?;
+            }
+            MidiEvent::PortStart { name: midi_monitor.get_port_name(addr)? }
+        }
+        seq::EventType::ClientExit => {
+            let addr: seq::Addr = ev.get_data().ok_or("Expected address")?;
+            MidiEvent::ClientExit { name: midi_monitor.get_port_name(addr)? }
+        }
+        seq::EventType::PortExit => {
+            let addr: seq::Addr = ev.get_data().ok_or("Expected address")?;
+            let name = midi_monitor.get_port_name(addr)?;
+            midi_monitor.remove_port_name(addr);
+            midi_monitor.connected.remove(&addr);
+            MidiEvent::PortExit { name }
+        }
+        seq::EventType::PortSubscribed => {
+            let conn: seq::Connect = ev.get_data().ok_or("Expected connection")?;
+            if midi_monitor.is_own_port(conn.dest) && conn.sender != ports::SYSTEM_ANNOUNCE {
+                let name = midi_monitor.get_port_name(conn.sender)?;
+                midi_monitor.connected.insert(conn.sender, name);
+            }
+            MidiEvent::PortSubscribed {
+                sender: midi_monitor.get_port_name(conn.sender)?,
+                dest: midi_monitor.get_port_name(conn.dest)?,
+            }
+        }
+        seq::EventType::PortUnsubscribed => {
+            let conn: seq::Connect = ev.get_data().ok_or("Expected connection")?;
+            if midi_monitor.is_own_port(conn.dest) {
+                midi_monitor.connected.remove(&conn.sender);
+            }
+            MidiEvent::PortUnsubscribed {
+                sender: midi_monitor.get_port_name(conn.sender)?,
+                dest: midi_monitor.get_port_name(conn.dest)?,
+            }
+        }
+        _ => MidiEvent::Unknown { debug: format!("{:?}", ev) },
+    };
+
+    Ok(Some(DecodedEvent { time: elapsed, processed: None, source, origin, event }))
+}
+
+// Controllers go through MPE, RPN/NRPN and 14 bit pairing, in that order.
+fn decode_controller(midi_monitor: &mut MidiMonitor, source: seq::Addr, data: seq::EvCtrl) -> Option<MidiEvent> {
+    if data.param == mpe::CC_SLIDE {
+        if let Some(expression) = midi_monitor.mpe_state(source).and_then(|mpe_state| mpe_state.slide(data.channel, data.value)) {
+            return Some(MidiEvent::MpeExpression { channel: data.channel, expression });
+        }
+    }
+    let state = midi_monitor.parameters
+        .entry((source, data.channel))
+        .or_default();
+    match state.update(data.param, data.value) {
+        rpn::ParameterUpdate::Selected => return None,
+        rpn::ParameterUpdate::Deselected => return Some(MidiEvent::ParameterNull { channel: data.channel }),
+        rpn::ParameterUpdate::Value(parameter) => {
+            let mpe_zones = midi_monitor.apply_parameter(source, data.channel, &parameter);
+            return Some(MidiEvent::Parameter { channel: data.channel, parameter, mpe_zones });
+        }
+        rpn::ParameterUpdate::None => {}
+    }
+    if !midi_monitor.pair_14bit {
+        return Some(MidiEvent::Controller { channel: data.channel, param: data.param, value: data.value });
+    }
+    let update = midi_monitor.controls14
+        .entry((source, data.channel))
+        .or_default()
+        .update(data.param, data.value);
+    match update {
+        control14::Control14Update::Value(param, value) => {
+            Some(MidiEvent::Controller14 { channel: data.channel, param, value: value as i32 })
+        }
+        control14::Control14Update::None => {
+            Some(MidiEvent::Controller { channel: data.channel, param: data.param, value: data.value })
+        }
+    }
+}
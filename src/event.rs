@@ -0,0 +1,164 @@
+/**
+ *  Terminal MIDI Monitor -- Shows MIDI Events on the terminal
+ *  Copyright (C) 2019 David Moreno / Coralbits SL <dmoreno@coralbits.com>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use alsa::seq;
+use crate::mpe;
+use crate::mtc;
+use crate::rpn;
+
+// Channel mode messages
+pub const CC_ALL_SOUND_OFF: u32 = 120;
+pub const CC_RESET_ALL_CONTROLLERS: u32 = 121;
+pub const CC_ALL_NOTES_OFF: u32 = 123;
+
+/// A MIDI event after decoding, with all the monitor state already applied
+/// (RPN, 14 bit pairing, MPE, MTC, clock...). How to show it is up to the output.
+#[derive(Clone, Debug)]
+pub enum MidiEvent {
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    NoteOff { channel: u8, note: u8, velocity: u8 },
+    PolyAftertouch { channel: u8, note: u8, pressure: u8 },
+    Controller { channel: u8, param: u32, value: i32 },
+    Controller14 { channel: u8, param: u32, value: i32 },
+    Parameter { channel: u8, parameter: rpn::Parameter, mpe_zones: Option<String> },
+    ParameterNull { channel: u8 },
+    PitchBend { channel: u8, value: i32 },
+    ProgramChange { channel: u8, program: i32 },
+    ChannelPressure { channel: u8, value: i32 },
+    MpeExpression { channel: u8, expression: mpe::NoteExpression },
+    // Warnings of the note checker. held is in seconds.
+    StuckNote { channel: u8, note: u8, velocity: u8, held: f64 },
+    OrphanNoteOff { channel: u8, note: u8 },
+    DoubleNoteOn { channel: u8, note: u8, velocity: u8, held: f64 },
+    SysEx { data: Vec<u8> },
+    SysExTruncated { data: Vec<u8> }, // Cut by a new F0 before its F7
+    MtcFullFrame { timecode: mtc::Timecode },
+    Mtc { timecode: mtc::Timecode },
+    MtcDropped { timecode: mtc::Timecode, frames: i64, quarter_frames: u32 },
+    MtcBackwards { timecode: mtc::Timecode },
+    // beat is true for the clocks on a beat, the only ones shown as text
+    Clock { bpm: f64, clock_pos: i32, bars_beats_ticks: String, beat: bool, stats: Option<String> },
+    SongPosition { value: i32, bars_beats_ticks: String },
+    SongSelect { song: i32 },
+    Start,
+    Stop,
+    Continue,
+    TuneRequest,
+    Reset,
+    Sensing,
+    ClientStart { name: String },
+    ClientExit { name: String },
+    PortStart { name: String },
+    PortExit { name: String },
+    PortSubscribed { sender: String, dest: String },
+    PortUnsubscribed { sender: String, dest: String },
+    Unknown { debug: String },
+}
+
+/// All the names `MidiEvent::type_name` can return.
+pub const TYPE_NAMES: &[&str] = &[
+    "noteon", "noteoff", "polyaftertouch", "cc", "cc14", "rpn", "nrpn", "rpnnull", "pitchbend",
+    "program", "chanpress", "mpe", "stucknote", "orphannoteoff", "doublenoteon", "sysex", "sysextruncated", "mtcfullframe", "mtc", "mtcdropped", "mtcbackwards",
+    "clock", "songpos", "songsel", "start", "stop", "continue", "tunerequest", "reset", "sensing",
+    "clientstart", "clientexit", "portstart", "portexit", "portsubscribed", "portunsubscribed",
+    "unknown",
+];
+
+impl MidiEvent {
+    /// Short name, for machine readable output and command line options.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            MidiEvent::NoteOn { .. } => "noteon",
+            MidiEvent::NoteOff { .. } => "noteoff",
+            MidiEvent::PolyAftertouch { .. } => "polyaftertouch",
+            MidiEvent::Controller { .. } => "cc",
+            MidiEvent::Controller14 { .. } => "cc14",
+            MidiEvent::Parameter { parameter, .. } => match parameter.kind {
+                rpn::ParameterKind::Registered => "rpn",
+                rpn::ParameterKind::NonRegistered => "nrpn",
+            },
+            MidiEvent::ParameterNull { .. } => "rpnnull",
+            MidiEvent::PitchBend { .. } => "pitchbend",
+            MidiEvent::ProgramChange { .. } => "program",
+            MidiEvent::ChannelPressure { .. } => "chanpress",
+            MidiEvent::MpeExpression { .. } => "mpe",
+            MidiEvent::StuckNote { .. } => "stucknote",
+            MidiEvent::OrphanNoteOff { .. } => "orphannoteoff",
+            MidiEvent::DoubleNoteOn { .. } => "doublenoteon",
+            MidiEvent::SysEx { .. } => "sysex",
+            MidiEvent::SysExTruncated { .. } => "sysextruncated",
+            MidiEvent::MtcFullFrame { .. } => "mtcfullframe",
+            MidiEvent::Mtc { .. } => "mtc",
+            MidiEvent::MtcDropped { .. } => "mtcdropped",
+            MidiEvent::MtcBackwards { .. } => "mtcbackwards",
+            MidiEvent::Clock { .. } => "clock",
+            MidiEvent::SongPosition { .. } => "songpos",
+            MidiEvent::SongSelect { .. } => "songsel",
+            MidiEvent::Start => "start",
+            MidiEvent::Stop => "stop",
+            MidiEvent::Continue => "continue",
+            MidiEvent::TuneRequest => "tunerequest",
+            MidiEvent::Reset => "reset",
+            MidiEvent::Sensing => "sensing",
+            MidiEvent::ClientStart { .. } => "clientstart",
+            MidiEvent::ClientExit { .. } => "clientexit",
+            MidiEvent::PortStart { .. } => "portstart",
+            MidiEvent::PortExit { .. } => "portexit",
+            MidiEvent::PortSubscribed { .. } => "portsubscribed",
+            MidiEvent::PortUnsubscribed { .. } => "portunsubscribed",
+            MidiEvent::Unknown { .. } => "unknown",
+        }
+    }
+
+    pub fn channel(&self) -> Option<u8> {
+        match self {
+            MidiEvent::NoteOn { channel, .. }
+            | MidiEvent::NoteOff { channel, .. }
+            | MidiEvent::PolyAftertouch { channel, .. }
+            | MidiEvent::Controller { channel, .. }
+            | MidiEvent::Controller14 { channel, .. }
+            | MidiEvent::Parameter { channel, .. }
+            | MidiEvent::ParameterNull { channel }
+            | MidiEvent::PitchBend { channel, .. }
+            | MidiEvent::ProgramChange { channel, .. }
+            | MidiEvent::ChannelPressure { channel, .. }
+            | MidiEvent::MpeExpression { channel, .. }
+            | MidiEvent::StuckNote { channel, .. }
+            | MidiEvent::OrphanNoteOff { channel, .. }
+            | MidiEvent::DoubleNoteOn { channel, .. } => Some(*channel),
+            _ => None,
+        }
+    }
+}
+
+/// A decoded event, with when and where it came from.
+#[derive(Clone, Debug)]
+pub struct DecodedEvent {
+    pub time: f64, // Seconds since the monitor started, as timestamped on arrival
+    pub processed: Option<f64>, // Seconds since the monitor started when processed, if shown
+    pub source: seq::Addr,
+    pub origin: String, // Client and port name of the source
+    pub event: MidiEvent,
+}
+
+#[cfg(test)]
+impl DecodedEvent {
+    /// Event from port 0 of the client, named Keyboard.
+    pub fn for_test(time: f64, client: i32, event: MidiEvent) -> DecodedEvent {
+        DecodedEvent { time, processed: None, source: seq::Addr { client, port: 0 }, origin: "Keyboard".to_string(), event }
+    }
+}
@@ -0,0 +1,519 @@
+/**
+ *  Terminal MIDI Monitor -- Shows MIDI Events on the terminal
+ *  Copyright (C) 2019 David Moreno / Coralbits SL <dmoreno@coralbits.com>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+// Filter expressions, as `type == cc && param in 1..=7 && value > 100 || (type == noteon && note < C2)`.
+//
+// Fields are named as in the JSON output. Bare words that are not fields are literals: event
+// type names, note names (C5 is 60, as shown) and controller names (`param == Modulation`).
+use std::error;
+use crate::event::{DecodedEvent, MidiEvent, TYPE_NAMES};
+use crate::{CC_MAP, PROGRAM_MAP};
+
+const FIELDS: &[&str] = &[
+    "type", "time", "source", "origin", "channel", "note", "velocity", "pressure", "param", "value",
+    "program", "msb", "lsb", "bend", "slide", "bpm", "clock_pos", "song", "length", "frames", "quarter_frames", "name",
+    "held",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Number(f64),
+    Str(String),
+    Bool(bool),
+    Missing, // The event has no such field
+}
+
+impl Value {
+    fn is_true(&self) -> bool {
+        match self {
+            Value::Number(number) => *number != 0.0,
+            Value::Str(string) => !string.is_empty(),
+            Value::Bool(boolean) => *boolean,
+            Value::Missing => false,
+        }
+    }
+
+    // Strings compared with numbers may be note or controller names.
+    fn to_number(&self) -> Option<f64> {
+        match self {
+            Value::Number(number) => Some(*number),
+            Value::Str(string) => string.parse().ok().or_else(|| note_number(string)).or_else(|| cc_number(string)),
+            Value::Bool(boolean) => Some(if *boolean { 1.0 } else { 0.0 }),
+            Value::Missing => None,
+        }
+    }
+}
+
+/// Note name, as C5 or F#3 or Eb2, to its note number. Same octaves as shown, C0 is 0.
+pub fn note_number(name: &str) -> Option<f64> {
+    let mut chars = name.chars();
+    let base = match chars.next()?.to_ascii_uppercase() {
+        'C' => 0,
+        'D' => 2,
+        'E' => 4,
+        'F' => 5,
+        'G' => 7,
+        'A' => 9,
+        'B' => 11,
+        _ => return None,
+    };
+    let rest = chars.as_str();
+    let (accidental, octave) = if let Some(octave) = rest.strip_prefix('#') {
+        (1, octave)
+    } else if let Some(octave) = rest.strip_prefix('b') {
+        (-1, octave)
+    } else {
+        (0, rest)
+    };
+    let octave: i32 = octave.parse().ok()?;
+    let note = octave * 12 + base + accidental;
+    if (0..=127).contains(&note) {
+        Some(note as f64)
+    } else {
+        None
+    }
+}
+
+/// Controller by its name in CC_MAP, ignoring case. Some names repeat, the lowest number wins.
+pub fn cc_number(name: &str) -> Option<f64> {
+    CC_MAP.iter()
+        .filter(|(_, cc_name)| cc_name.eq_ignore_ascii_case(name))
+        .map(|(param, _)| *param)
+        .min()
+        .map(|param| param as f64)
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum CompareOp {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+#[derive(Debug)]
+enum Node {
+    Literal(Value),
+    Field(String),
+    Not(Box<Node>),
+    And(Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+    Compare(CompareOp, Box<Node>, Box<Node>),
+    InRange(Box<Node>, Box<Node>, Box<Node>, bool), // Inclusive end if true
+    InList(Box<Node>, Vec<Node>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f64),
+    Word(String),
+    Str(String),
+    Op(&'static str),
+}
+
+const OPERATORS: &[&str] = &["..=", "==", "!=", "<=", ">=", "&&", "||", "..", "<", ">", "!", "-", "(", ")", "[", "]", ","];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, Box<dyn error::Error>> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while pos < chars.len() {
+        let c = chars[pos];
+        if c.is_whitespace() {
+            pos += 1;
+        } else if c.is_ascii_digit() {
+            let start = pos;
+            while pos < chars.len() && chars[pos].is_ascii_digit() {
+                pos += 1;
+            }
+            // A dot is decimal only if followed by a digit, as 1..7 is a range.
+            if pos + 1 < chars.len() && chars[pos] == '.' && chars[pos + 1].is_ascii_digit() {
+                pos += 1;
+                while pos < chars.len() && chars[pos].is_ascii_digit() {
+                    pos += 1;
+                }
+            }
+            let number: String = chars[start..pos].iter().collect();
+            tokens.push(Token::Number(number.parse()?));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = pos;
+            while pos < chars.len() && (chars[pos].is_alphanumeric() || chars[pos] == '_' || chars[pos] == '#') {
+                pos += 1;
+            }
+            tokens.push(Token::Word(chars[start..pos].iter().collect()));
+        } else if c == '"' || c == '\'' {
+            let end = chars[pos + 1..].iter().position(|&e| e == c).ok_or("Unterminated string in filter")?;
+            tokens.push(Token::Str(chars[pos + 1..pos + 1 + end].iter().collect()));
+            pos += end + 2;
+        } else {
+            let rest: String = chars[pos..].iter().take(3).collect();
+            let op = OPERATORS.iter().find(|op| rest.starts_with(*op))
+                .ok_or_else(|| format!("Unexpected {} in filter", c))?;
+            tokens.push(Token::Op(op));
+            pos += op.len();
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn accept(&mut self, op: &str) -> bool {
+        match self.peek() {
+            Some(Token::Op(o)) if *o == op => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn accept_word(&mut self, word: &str) -> bool {
+        match self.peek() {
+            Some(Token::Word(w)) if w == word => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), Box<dyn error::Error>> {
+        if self.accept(op) {
+            Ok(())
+        } else {
+            Err(format!("Expected {} in filter", op).into())
+        }
+    }
+
+    fn or(&mut self) -> Result<Node, Box<dyn error::Error>> {
+        let mut node = self.and()?;
+        while self.accept("||") {
+            node = Node::Or(Box::new(node), Box::new(self.and()?));
+        }
+        Ok(node)
+    }
+
+    fn and(&mut self) -> Result<Node, Box<dyn error::Error>> {
+        let mut node = self.not()?;
+        while self.accept("&&") {
+            node = Node::And(Box::new(node), Box::new(self.not()?));
+        }
+        Ok(node)
+    }
+
+    fn not(&mut self) -> Result<Node, Box<dyn error::Error>> {
+        if self.accept("!") {
+            return Ok(Node::Not(Box::new(self.not()?)));
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Node, Box<dyn error::Error>> {
+        let left = self.value()?;
+        let ops = [
+            ("==", CompareOp::Equal),
+            ("!=", CompareOp::NotEqual),
+            ("<=", CompareOp::LessEqual),
+            (">=", CompareOp::GreaterEqual),
+            ("<", CompareOp::Less),
+            (">", CompareOp::Greater),
+        ];
+        for (op, compare) in ops.iter() {
+            if self.accept(op) {
+                let right = type_literal(&left, self.value()?);
+                return Ok(Node::Compare(*compare, Box::new(left), Box::new(right)));
+            }
+        }
+        if self.accept_word("in") {
+            if self.accept("[") {
+                let mut items = vec![type_literal(&left, self.value()?)];
+                while self.accept(",") {
+                    items.push(type_literal(&left, self.value()?));
+                }
+                self.expect("]")?;
+                return Ok(Node::InList(Box::new(left), items));
+            }
+            let start = self.value()?;
+            let inclusive = if self.accept("..=") {
+                true
+            } else if self.accept("..") {
+                false
+            } else {
+                return Err("Expected a range as 1..=7 or a list as [1, 7] after in".into());
+            };
+            return Ok(Node::InRange(Box::new(left), Box::new(start), Box::new(self.value()?), inclusive));
+        }
+        Ok(left)
+    }
+
+    fn value(&mut self) -> Result<Node, Box<dyn error::Error>> {
+        if self.accept("(") {
+            let node = self.or()?;
+            self.expect(")")?;
+            return Ok(node);
+        }
+        // Pitch bend and MPE bend are signed
+        if self.accept("-") {
+            return match self.peek().cloned() {
+                Some(Token::Number(number)) => {
+                    self.pos += 1;
+                    Ok(Node::Literal(Value::Number(-number)))
+                }
+                _ => Err("Expected a number after - in filter".into()),
+            };
+        }
+        let token = self.peek().cloned().ok_or("Unexpected end of filter")?;
+        self.pos += 1;
+        match token {
+            Token::Number(number) => Ok(Node::Literal(Value::Number(number))),
+            Token::Str(string) => Ok(Node::Literal(Value::Str(string))),
+            Token::Word(word) => {
+                if FIELDS.contains(&word.as_str()) {
+                    Ok(Node::Field(word))
+                } else if word == "true" || word == "false" {
+                    Ok(Node::Literal(Value::Bool(word == "true")))
+                } else if TYPE_NAMES.contains(&word.as_str()) || note_number(&word).is_some() || cc_number(&word).is_some() {
+                    Ok(Node::Literal(Value::Str(word)))
+                } else {
+                    Err(format!(
+                        "Unknown name {} in filter. Fields are {}. Use quotes for other strings.",
+                        word, FIELDS.join(", ")
+                    ).into())
+                }
+            }
+            Token::Op(op) => Err(format!("Unexpected {} in filter", op).into()),
+        }
+    }
+}
+
+// Compared with the type, `program` is the type name, not the field.
+fn type_literal(left: &Node, right: Node) -> Node {
+    match (left, right) {
+        (Node::Field(field), Node::Field(word)) if field == "type" && TYPE_NAMES.contains(&word.as_str()) => {
+            Node::Literal(Value::Str(word))
+        }
+        (_, right) => right,
+    }
+}
+
+fn token_text(token: &Token) -> String {
+    match token {
+        Token::Number(number) => number.to_string(),
+        Token::Word(word) => word.clone(),
+        Token::Str(string) => format!("\"{}\"", string),
+        Token::Op(op) => op.to_string(),
+    }
+}
+
+/// A parsed filter expression.
+#[derive(Debug)]
+pub struct Expr {
+    root: Node,
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Expr, Box<dyn error::Error>> {
+        let mut parser = Parser { tokens: tokenize(text)?, pos: 0 };
+        let root = parser.or()?;
+        if let Some(token) = parser.peek() {
+            return Err(format!("Unexpected {} in filter", token_text(token)).into());
+        }
+        Ok(Expr { root })
+    }
+
+    pub fn matches(&self, ev: &DecodedEvent) -> bool {
+        eval(&self.root, ev).is_true()
+    }
+}
+
+fn number(value: impl Into<f64>) -> Value {
+    Value::Number(value.into())
+}
+
+fn field(ev: &DecodedEvent, name: &str) -> Value {
+    match name {
+        "type" => return Value::Str(ev.event.type_name().to_string()),
+        "time" => return number(ev.time),
+        "source" => return Value::Str(format!("{}:{}", ev.source.client, ev.source.port)),
+        "origin" => return Value::Str(ev.origin.clone()),
+        "channel" => return ev.event.channel().map(number).unwrap_or(Value::Missing),
+        _ => {}
+    }
+    match (&ev.event, name) {
+        (MidiEvent::NoteOn { note, .. }, "note")
+        | (MidiEvent::NoteOff { note, .. }, "note")
+        | (MidiEvent::PolyAftertouch { note, .. }, "note")
+        | (MidiEvent::StuckNote { note, .. }, "note")
+        | (MidiEvent::OrphanNoteOff { note, .. }, "note")
+        | (MidiEvent::DoubleNoteOn { note, .. }, "note") => number(*note),
+        (MidiEvent::NoteOn { velocity, .. }, "velocity")
+        | (MidiEvent::NoteOff { velocity, .. }, "velocity")
+        | (MidiEvent::StuckNote { velocity, .. }, "velocity")
+        | (MidiEvent::DoubleNoteOn { velocity, .. }, "velocity") => number(*velocity),
+        (MidiEvent::StuckNote { held, .. }, "held") | (MidiEvent::DoubleNoteOn { held, .. }, "held") => number(*held),
+        (MidiEvent::PolyAftertouch { pressure, .. }, "pressure") => number(*pressure),
+        (MidiEvent::Controller { param, .. }, "param") | (MidiEvent::Controller14 { param, .. }, "param") => number(*param),
+        (MidiEvent::Controller { value, .. }, "value")
+        | (MidiEvent::Controller14 { value, .. }, "value")
+        | (MidiEvent::PitchBend { value, .. }, "value")
+        | (MidiEvent::ChannelPressure { value, .. }, "value")
+        | (MidiEvent::SongPosition { value, .. }, "value") => number(*value),
+        (MidiEvent::Parameter { parameter, .. }, "msb") => number(parameter.msb),
+        (MidiEvent::Parameter { parameter, .. }, "lsb") => number(parameter.lsb),
+        (MidiEvent::Parameter { parameter, .. }, "value") => number(parameter.value),
+        (MidiEvent::Parameter { parameter, .. }, "name") => Value::Str(parameter.name().to_string()),
+        (MidiEvent::ProgramChange { program, .. }, "program") => number(*program),
+        (MidiEvent::Controller { param, .. }, "name") | (MidiEvent::Controller14 { param, .. }, "name") => {
+            Value::Str(CC_MAP.get(param).cloned().unwrap_or_else(|| "Unknown".to_string()))
+        }
+        (MidiEvent::ProgramChange { program, .. }, "name") => {
+            Value::Str(PROGRAM_MAP.get(&(*program as u32)).cloned().unwrap_or_else(|| "Unknown".to_string()))
+        }
+        (MidiEvent::MpeExpression { expression, .. }, "note") => expression.note.map(number).unwrap_or(Value::Missing),
+        (MidiEvent::MpeExpression { expression, .. }, "bend") => number(expression.bend),
+        (MidiEvent::MpeExpression { expression, .. }, "slide") => expression.slide.map(number).unwrap_or(Value::Missing),
+        (MidiEvent::MpeExpression { expression, .. }, "pressure") => expression.pressure.map(number).unwrap_or(Value::Missing),
+        (MidiEvent::SysEx { data }, "length") | (MidiEvent::SysExTruncated { data }, "length") => number(data.len() as u32),
+        (MidiEvent::MtcDropped { frames, .. }, "frames") => number(*frames as f64),
+        (MidiEvent::MtcDropped { quarter_frames, .. }, "quarter_frames") => number(*quarter_frames),
+        (MidiEvent::Clock { bpm, .. }, "bpm") => number(*bpm),
+        (MidiEvent::Clock { clock_pos, .. }, "clock_pos") => number(*clock_pos),
+        (MidiEvent::SongSelect { song }, "song") => number(*song),
+        (MidiEvent::ClientStart { name }, "name")
+        | (MidiEvent::ClientExit { name }, "name")
+        | (MidiEvent::PortStart { name }, "name")
+        | (MidiEvent::PortExit { name }, "name") => Value::Str(name.clone()),
+        _ => Value::Missing,
+    }
+}
+
+// Missing fields never compare, not even as different.
+fn compare(op: CompareOp, left: &Value, right: &Value) -> bool {
+    if *left == Value::Missing || *right == Value::Missing {
+        return false;
+    }
+    let ordering = match (left, right) {
+        (Value::Str(left), Value::Str(right)) => Some(left.to_lowercase().cmp(&right.to_lowercase())),
+        _ => match (left.to_number(), right.to_number()) {
+            (Some(left), Some(right)) => left.partial_cmp(&right),
+            _ => None,
+        },
+    };
+    let ordering = match ordering {
+        Some(ordering) => ordering,
+        None => return op == CompareOp::NotEqual,
+    };
+    match op {
+        CompareOp::Equal => ordering.is_eq(),
+        CompareOp::NotEqual => ordering.is_ne(),
+        CompareOp::Less => ordering.is_lt(),
+        CompareOp::LessEqual => ordering.is_le(),
+        CompareOp::Greater => ordering.is_gt(),
+        CompareOp::GreaterEqual => ordering.is_ge(),
+    }
+}
+
+fn eval(node: &Node, ev: &DecodedEvent) -> Value {
+    match node {
+        Node::Literal(value) => value.clone(),
+        Node::Field(name) => field(ev, name),
+        Node::Not(node) => Value::Bool(!eval(node, ev).is_true()),
+        Node::And(left, right) => Value::Bool(eval(left, ev).is_true() && eval(right, ev).is_true()),
+        Node::Or(left, right) => Value::Bool(eval(left, ev).is_true() || eval(right, ev).is_true()),
+        Node::Compare(op, left, right) => Value::Bool(compare(*op, &eval(left, ev), &eval(right, ev))),
+        Node::InRange(value, start, end, inclusive) => {
+            let value = eval(value, ev);
+            let end_op = if *inclusive { CompareOp::LessEqual } else { CompareOp::Less };
+            Value::Bool(
+                compare(CompareOp::GreaterEqual, &value, &eval(start, ev)) && compare(end_op, &value, &eval(end, ev))
+            )
+        }
+        Node::InList(value, items) => {
+            let value = eval(value, ev);
+            Value::Bool(items.iter().any(|item| compare(CompareOp::Equal, &value, &eval(item, ev))))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(filter: &str, event: MidiEvent) -> bool {
+        Expr::parse(filter).unwrap().matches(&DecodedEvent::for_test(1.5, 20, event))
+    }
+
+    #[test]
+    fn negative_numbers() {
+        let bend = || MidiEvent::PitchBend { channel: 0, value: -5000 };
+        assert!(matches("type == pitchbend && value < -4000", bend()));
+        assert!(!matches("value < -6000", bend()));
+        assert!(matches("value in -8192..=0", bend()));
+        assert!(matches("value > -5000.5", bend()));
+        assert!(Expr::parse("value < -velocity").is_err());
+    }
+
+    #[test]
+    fn notes_types_and_lists() {
+        let note = || MidiEvent::NoteOn { channel: 0, note: 60, velocity: 100 };
+        assert!(matches("type == noteon && note == C5", note()));
+        assert!(matches("note in [C5, D5] && velocity >= 100", note()));
+        assert!(matches("!(velocity < 64) || type == program", note()));
+        assert!(!matches("note in 61..=72", note()));
+        assert!(matches("origin == 'Keyboard' && time > 1", note()));
+    }
+
+    #[test]
+    fn missing_fields_do_not_match() {
+        assert!(!matches("velocity > 0", MidiEvent::Start));
+        assert!(matches("!(velocity > 0)", MidiEvent::Start));
+    }
+
+    #[test]
+    fn ranges_are_numbers_not_decimals() {
+        let program = || MidiEvent::ProgramChange { channel: 0, program: 7 };
+        assert!(!matches("program in 1..7", program()));
+        assert!(matches("program in 1..=7", program()));
+    }
+
+    #[test]
+    fn controller_and_program_names() {
+        assert!(matches("name == 'modulation'", MidiEvent::Controller { channel: 0, param: 1, value: 64 }));
+        assert!(matches("name == 'Acoustic Grand Piano'", MidiEvent::ProgramChange { channel: 0, program: 1 }));
+        assert!(!matches("name == 'Modulation'", MidiEvent::ProgramChange { channel: 0, program: 1 }));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(Expr::parse("note ==").is_err());
+        assert!(Expr::parse("nope == 1").is_err());
+        assert!(Expr::parse("note == 'C5").is_err());
+        assert!(Expr::parse("(note == 1").is_err());
+        assert!(Expr::parse("note == 1 )").is_err());
+    }
+}
@@ -0,0 +1,167 @@
+/**
+ *  Terminal MIDI Monitor -- Shows MIDI Events on the terminal
+ *  Copyright (C) 2019 David Moreno / Coralbits SL <dmoreno@coralbits.com>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use regex::Regex;
+use std::collections::{BTreeMap, HashSet};
+use std::error;
+use crate::event::{DecodedEvent, TYPE_NAMES};
+use crate::expr::Expr;
+
+// Names for several event types at once, besides the type names themselves.
+const TYPE_GROUPS: &[(&str, &[&str])] = &[
+    ("note", &["noteon", "noteoff", "polyaftertouch"]),
+    ("notecheck", &["stucknote", "orphannoteoff", "doublenoteon"]),
+    ("cc", &["cc", "cc14"]),
+    ("rpn", &["rpn", "nrpn", "rpnnull"]),
+    ("sysex", &["sysex", "sysextruncated"]),
+    ("mtc", &["mtc", "mtcfullframe", "mtcdropped", "mtcbackwards"]),
+    ("transport", &["start", "stop", "continue", "songpos"]),
+    ("connections", &["clientstart", "clientexit", "portstart", "portexit", "portsubscribed", "portunsubscribed"]),
+];
+
+/// Which events to show, from the command line options. Counts the filtered out ones.
+pub struct EventFilter {
+    channels: Option<HashSet<u8>>, // 0 based, as in the events
+    only: Option<HashSet<&'static str>>,
+    ignore: HashSet<&'static str>,
+    source: Option<Regex>,
+    expression: Option<Expr>,
+    filtered: BTreeMap<&'static str, u64>,
+}
+
+// Comma separated list of type or group names, as the type names.
+pub fn parse_types(types: &str) -> Result<HashSet<&'static str>, Box<dyn error::Error>> {
+    let mut result = HashSet::new();
+    for name in types.split(',').map(|name| name.trim().to_lowercase()).filter(|name| !name.is_empty()) {
+        if let Some((_, group)) = TYPE_GROUPS.iter().find(|(group, _)| *group == name) {
+            result.extend(group.iter());
+        } else if let Some(type_name) = TYPE_NAMES.iter().find(|type_name| **type_name == name) {
+            result.insert(*type_name);
+        } else {
+            let groups = TYPE_GROUPS.iter().map(|(group, _)| *group).filter(|group| !TYPE_NAMES.contains(group));
+            let valid: Vec<&str> = groups.chain(TYPE_NAMES.iter().cloned()).collect();
+            return Err(format!("Unknown event type {}. Valid types are: {}", name, valid.join(", ")).into());
+        }
+    }
+    Ok(result)
+}
+
+// Channels are 1 based on the command line.
+fn parse_channels(channels: &str) -> Result<HashSet<u8>, Box<dyn error::Error>> {
+    let mut result = HashSet::new();
+    for channel in channels.split(',').map(|channel| channel.trim()).filter(|channel| !channel.is_empty()) {
+        match channel.parse::<u8>() {
+            Ok(channel) if (1..=16).contains(&channel) => result.insert(channel - 1),
+            _ => return Err(format!("Invalid channel {}, must be 1 to 16", channel).into()),
+        };
+    }
+    Ok(result)
+}
+
+impl EventFilter {
+    pub fn new(
+        channels: Option<&str>,
+        only: Option<&str>,
+        ignore: Option<&str>,
+        source: Option<&str>,
+        expression: Option<&str>,
+    ) -> Result<EventFilter, Box<dyn error::Error>> {
+        Ok(EventFilter {
+            channels: channels.map(parse_channels).transpose()?,
+            only: only.map(parse_types).transpose()?,
+            ignore: ignore.map(parse_types).transpose()?.unwrap_or_default(),
+            source: source.map(Regex::new).transpose().map_err(|err| format!("Invalid source regex: {}", err))?,
+            expression: expression.map(Expr::parse).transpose()?,
+            filtered: BTreeMap::new(),
+        })
+    }
+
+    // Events without channel (clock, sysex...) pass the channel filter.
+    fn matches(&self, ev: &DecodedEvent) -> bool {
+        let type_name = ev.event.type_name();
+        if let (Some(channels), Some(channel)) = (self.channels.as_ref(), ev.event.channel()) {
+            if !channels.contains(&channel) {
+                return false;
+            }
+        }
+        if let Some(only) = self.only.as_ref() {
+            if !only.contains(type_name) {
+                return false;
+            }
+        }
+        if self.ignore.contains(type_name) {
+            return false;
+        }
+        if let Some(source) = self.source.as_ref() {
+            if !source.is_match(&ev.origin) {
+                return false;
+            }
+        }
+        match self.expression.as_ref() {
+            Some(expression) => expression.matches(ev),
+            None => true,
+        }
+    }
+
+    /// Whether to show the event. If not, counts it.
+    pub fn accept(&mut self, ev: &DecodedEvent) -> bool {
+        if self.matches(ev) {
+            return true;
+        }
+        *self.filtered.entry(ev.event.type_name()).or_insert(0) += 1;
+        false
+    }
+
+    /// Count of filtered out events per type, if any.
+    pub fn summary(&self) -> Option<String> {
+        if self.filtered.is_empty() {
+            return None;
+        }
+        let total: u64 = self.filtered.values().sum();
+        let counts: Vec<String> = self.filtered.iter().map(|(type_name, count)| format!("{} {}", type_name, count)).collect();
+        Some(format!("{} events | {}", total, counts.join(", ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups_and_types() {
+        let types = parse_types("note, CC,program").unwrap();
+        for name in &["noteon", "noteoff", "polyaftertouch", "cc", "cc14", "program"] {
+            assert!(types.contains(name), "{} missing", name);
+        }
+        assert_eq!(types.len(), 6);
+        let types = parse_types("rpn").unwrap();
+        assert!(types.contains("rpn") && types.contains("nrpn") && types.contains("rpnnull"));
+    }
+
+    #[test]
+    fn unknown_type() {
+        let err = parse_types("noteon,nope").unwrap_err().to_string();
+        assert!(err.starts_with("Unknown event type nope"), "{}", err);
+    }
+
+    #[test]
+    fn invalid_channels() {
+        assert!(parse_channels("1,16").is_ok());
+        assert!(parse_channels("0").is_err());
+        assert!(parse_channels("17").is_err());
+    }
+}
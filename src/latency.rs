@@ -0,0 +1,236 @@
+/**
+ *  Terminal MIDI Monitor -- Shows MIDI Events on the terminal
+ *  Copyright (C) 2019 David Moreno / Coralbits SL <dmoreno@coralbits.com>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use alsa::seq;
+use std::error;
+use crate::send;
+
+const PROBE_TIMEOUT: f64 = 1.0; // Seconds until a probe is lost
+const PROBE_CHANNEL: u8 = 15;
+const PROBE_NOTE: u8 = 0;
+const SYSEX_NON_COMMERCIAL: u8 = 0x7D;
+const SYSEX_PROBE: u8 = 0x4C; // 'L'
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ProbeKind {
+    Note, // Note ON and OFF at channel 16, note 0, the probe number in the velocity
+    SysEx, // Non commercial SysEx with the probe number
+}
+
+impl ProbeKind {
+    pub fn from_name(name: &str) -> Result<ProbeKind, Box<dyn error::Error>> {
+        match name {
+            "note" => Ok(ProbeKind::Note),
+            "sysex" => Ok(ProbeKind::SysEx),
+            _ => Err(format!("Unknown probe type {}", name).into()),
+        }
+    }
+}
+
+/// Sends probes to a port and times their echo back at the input port.
+pub struct LatencyTest {
+    kind: ProbeKind,
+    count: usize,
+    interval: f64, // Seconds between probes
+    dest: seq::Addr,
+    port: i32,
+    sent: usize,
+    pending: Option<(usize, f64)>, // Probe number and when it was sent
+    last_sent: Option<f64>,
+    samples: Vec<f64>, // Round trip, in seconds
+    lost: usize,
+}
+
+// Nearest rank percentile of sorted samples.
+fn percentile(sorted: &[f64], percent: f64) -> f64 {
+    let rank = ((percent / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+impl LatencyTest {
+    pub fn new(kind: ProbeKind, count: usize, interval: f64, dest: seq::Addr, port: i32) -> LatencyTest {
+        LatencyTest { kind, count, interval, dest, port, sent: 0, pending: None, last_sent: None, samples: Vec::new(), lost: 0 }
+    }
+
+    fn probe_events(&self, probe: usize) -> Vec<seq::Event<'static>> {
+        match self.kind {
+            ProbeKind::Note => {
+                let velocity = (probe % 127 + 1) as u8;
+                let note = |kind, velocity| {
+                    seq::Event::new(kind, &seq::EvNote { channel: PROBE_CHANNEL, note: PROBE_NOTE, velocity, off_velocity: 0, duration: 0 })
+                };
+                vec![note(seq::EventType::Noteon, velocity), note(seq::EventType::Noteoff, 0)]
+            }
+            ProbeKind::SysEx => {
+                let data = vec![0xF0, SYSEX_NON_COMMERCIAL, SYSEX_PROBE, (probe >> 7) as u8 & 0x7F, probe as u8 & 0x7F, 0xF7];
+                vec![seq::Event::new_ext(seq::EventType::Sysex, data)]
+            }
+        }
+    }
+
+    // Whether the event is the echo of the probe, or other part of a probe (Note OFF).
+    fn is_probe(&self, ev: &seq::Event, probe: Option<usize>) -> bool {
+        match (self.kind, ev.get_type()) {
+            (ProbeKind::Note, seq::EventType::Noteon) | (ProbeKind::Note, seq::EventType::Noteoff) => {
+                let note: seq::EvNote = match ev.get_data() {
+                    Some(note) => note,
+                    None => return false,
+                };
+                if note.channel != PROBE_CHANNEL || note.note != PROBE_NOTE {
+                    return false;
+                }
+                match probe {
+                    Some(probe) => ev.get_type() == seq::EventType::Noteon && note.velocity as usize == probe % 127 + 1,
+                    None => true,
+                }
+            }
+            (ProbeKind::SysEx, seq::EventType::Sysex) => {
+                let data = ev.get_ext().unwrap_or(&[]);
+                if data.len() != 6 || data[1] != SYSEX_NON_COMMERCIAL || data[2] != SYSEX_PROBE {
+                    return false;
+                }
+                match probe {
+                    Some(probe) => (data[3] as usize) << 7 | data[4] as usize == probe & 0x3FFF,
+                    None => true,
+                }
+            }
+            _ => false,
+        }
+    }
+
+    /// Sends the next probe when it is time, and gives up on the lost ones. `now` is in seconds
+    /// since the monitor started. Returns the probe lost, if any.
+    pub fn tick(&mut self, seq: &seq::Seq, now: f64) -> Result<Option<usize>, Box<dyn error::Error>> {
+        let mut lost = None;
+        if let Some((probe, sent)) = self.pending {
+            if now - sent < PROBE_TIMEOUT {
+                return Ok(None);
+            }
+            self.lost += 1;
+            self.pending = None;
+            lost = Some(probe);
+        }
+        let due = self.last_sent.map(|last_sent| now - last_sent >= self.interval).unwrap_or(true);
+        if self.sent < self.count && due {
+            let probe = self.sent + 1;
+            send::send(seq, self.port, self.dest, &mut self.probe_events(probe))?;
+            self.sent = probe;
+            self.pending = Some((probe, now));
+            self.last_sent = Some(now);
+        }
+        Ok(lost)
+    }
+
+    /// Whether the event is part of any probe, not to be shown.
+    pub fn is_probe_part(&self, ev: &seq::Event) -> bool {
+        self.is_probe(ev, None)
+    }
+
+    /// Returns the probe number and its round trip in seconds, if the event is the echo of the pending probe.
+    pub fn receive(&mut self, ev: &seq::Event, now: f64) -> Option<(usize, f64)> {
+        let (probe, sent) = self.pending?;
+        if !self.is_probe(ev, Some(probe)) {
+            return None;
+        }
+        self.pending = None;
+        self.samples.push(now - sent);
+        Some((probe, now - sent))
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.sent >= self.count && self.pending.is_none()
+    }
+
+    /// Round trip statistics, in milliseconds. Jitter is the mean difference between consecutive probes.
+    pub fn summary(&self) -> String {
+        if self.samples.is_empty() {
+            return format!("{} probes, all lost. Is the echo connected to the input?", self.sent);
+        }
+        let samples: Vec<f64> = self.samples.iter().map(|sample| sample * 1000.0).collect();
+        let jitter = samples.windows(2).map(|pair| (pair[1] - pair[0]).abs()).sum::<f64>() / (samples.len().max(2) - 1) as f64;
+        let average = samples.iter().sum::<f64>() / samples.len() as f64;
+        let mut sorted = samples;
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        format!(
+            "{} probes, {} lost | min {:.3} ms | avg {:.3} ms | max {:.3} ms | p50 {:.3} ms | p95 {:.3} ms | p99 {:.3} ms | jitter {:.3} ms",
+            self.sent,
+            self.lost,
+            sorted[0],
+            average,
+            sorted[sorted.len() - 1],
+            percentile(&sorted, 50.0),
+            percentile(&sorted, 95.0),
+            percentile(&sorted, 99.0),
+            jitter
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test(kind: ProbeKind) -> LatencyTest {
+        LatencyTest::new(kind, 10, 0.1, seq::Addr { client: 20, port: 0 }, 1)
+    }
+
+    #[test]
+    fn percentiles() {
+        let sorted: Vec<f64> = (1..=100).map(|n| n as f64).collect();
+        assert_eq!(percentile(&sorted, 50.0), 50.0);
+        assert_eq!(percentile(&sorted, 95.0), 95.0);
+        assert_eq!(percentile(&sorted, 0.0), 1.0);
+        assert_eq!(percentile(&[3.0], 99.0), 3.0);
+    }
+
+    #[test]
+    fn summary() {
+        let mut latency = test(ProbeKind::Note);
+        assert_eq!(latency.summary(), "0 probes, all lost. Is the echo connected to the input?");
+        latency.sent = 4;
+        latency.lost = 1;
+        latency.samples = vec![0.002, 0.004, 0.003];
+        assert_eq!(
+            latency.summary(),
+            "4 probes, 1 lost | min 2.000 ms | avg 3.000 ms | max 4.000 ms | p50 3.000 ms | p95 4.000 ms | p99 4.000 ms | jitter 1.500 ms"
+        );
+    }
+
+    #[test]
+    fn receive_note_probe() {
+        let mut latency = test(ProbeKind::Note);
+        let events = latency.probe_events(3);
+        assert!(events.iter().all(|ev| latency.is_probe_part(ev)));
+        latency.pending = Some((3, 1.0));
+        // Another probe number is not the echo
+        assert!(latency.receive(&latency.probe_events(4)[0], 1.001).is_none());
+        let (probe, round_trip) = latency.receive(&events[0], 1.0025).unwrap();
+        assert_eq!(probe, 3);
+        assert!((round_trip - 0.0025).abs() < 1e-9);
+        assert!(latency.pending.is_none());
+    }
+
+    #[test]
+    fn receive_sysex_probe() {
+        let mut latency = test(ProbeKind::SysEx);
+        let events = latency.probe_events(300);
+        latency.pending = Some((300, 2.0));
+        assert_eq!(latency.receive(&events[0], 2.01).map(|(probe, _)| probe), Some(300));
+        let other = seq::Event::new_ext(seq::EventType::Sysex, vec![0xF0, 0x7E, 0x7F, 0x06, 0x01, 0xF7]);
+        assert!(!latency.is_probe_part(&other));
+    }
+}
@@ -0,0 +1,327 @@
+/**
+ *  Terminal MIDI Monitor -- Shows MIDI Events on the terminal
+ *  Copyright (C) 2019 David Moreno / Coralbits SL <dmoreno@coralbits.com>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use alsa::seq;
+use alsa_sys as alsa_ffi;
+use colored::*;
+use std::error;
+use std::ffi::CString;
+use std::ptr;
+use crate::output::JsonObject;
+use crate::ports::PortDesc;
+
+const SND_SEQ_OPEN_DUPLEX: i32 = 3;
+
+const CAPABILITY_NAMES: &[(seq::PortCap, &str)] = &[
+    (seq::READ, "read"),
+    (seq::WRITE, "write"),
+    (seq::SYNC_READ, "sync-read"),
+    (seq::SYNC_WRITE, "sync-write"),
+    (seq::DUPLEX, "duplex"),
+    (seq::SUBS_READ, "subs-read"),
+    (seq::SUBS_WRITE, "subs-write"),
+    (seq::NO_EXPORT, "no-export"),
+];
+
+const TYPE_NAMES: &[(seq::PortType, &str)] = &[
+    (seq::SPECIFIC, "specific"),
+    (seq::MIDI_GENERIC, "midi-generic"),
+    (seq::MIDI_GM, "midi-gm"),
+    (seq::MIDI_GS, "midi-gs"),
+    (seq::MIDI_XG, "midi-xg"),
+    (seq::MIDI_MT32, "midi-mt32"),
+    (seq::MIDI_GM2, "midi-gm2"),
+    (seq::SYNTH, "synth"),
+    (seq::DIRECT_SAMPLE, "direct-sample"),
+    (seq::SAMPLE, "sample"),
+    (seq::HARDWARE, "hardware"),
+    (seq::SOFTWARE, "software"),
+    (seq::SYNTHESIZER, "synthesizer"),
+    (seq::PORT, "port"),
+    (seq::APPLICATION, "application"),
+];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ListFormat {
+    Tree,
+    Json,
+    Dot,
+}
+
+impl ListFormat {
+    pub fn from_name(name: &str) -> Result<ListFormat, Box<dyn error::Error>> {
+        match name {
+            "tree" => Ok(ListFormat::Tree),
+            "json" => Ok(ListFormat::Json),
+            "dot" => Ok(ListFormat::Dot),
+            _ => Err(format!("Unknown list format {}", name).into()),
+        }
+    }
+}
+
+// The alsa crate has no subscription queries, so they go through alsa-sys with a handle of its own.
+struct SubscriptionQuery(*mut alsa_ffi::snd_seq_t);
+
+impl SubscriptionQuery {
+    fn open() -> Result<SubscriptionQuery, Box<dyn error::Error>> {
+        let mut handle = ptr::null_mut();
+        let name = CString::new("default")?;
+        let err = unsafe { alsa_ffi::snd_seq_open(&mut handle, name.as_ptr(), SND_SEQ_OPEN_DUPLEX, 0) };
+        if err < 0 {
+            return Err(format!("Could not open the ALSA sequencer to query subscriptions ({})", err).into());
+        }
+        Ok(SubscriptionQuery(handle))
+    }
+
+    // Ports this one sends to (READ), or receives from (WRITE).
+    fn subscribers(&self, root: seq::Addr, kind: alsa_ffi::snd_seq_query_subs_type_t) -> Vec<seq::Addr> {
+        let mut subscribers = Vec::new();
+        let mut query = ptr::null_mut();
+        if unsafe { alsa_ffi::snd_seq_query_subscribe_malloc(&mut query) } < 0 {
+            return subscribers;
+        }
+        let root = alsa_ffi::snd_seq_addr_t { client: root.client as u8, port: root.port as u8 };
+        unsafe {
+            alsa_ffi::snd_seq_query_subscribe_set_root(query, &root);
+            alsa_ffi::snd_seq_query_subscribe_set_type(query, kind);
+            alsa_ffi::snd_seq_query_subscribe_set_index(query, 0);
+            while alsa_ffi::snd_seq_query_port_subscribers(self.0, query) >= 0 {
+                let addr = &*alsa_ffi::snd_seq_query_subscribe_get_addr(query);
+                subscribers.push(seq::Addr { client: addr.client as i32, port: addr.port as i32 });
+                let index = alsa_ffi::snd_seq_query_subscribe_get_index(query);
+                alsa_ffi::snd_seq_query_subscribe_set_index(query, index + 1);
+            }
+            alsa_ffi::snd_seq_query_subscribe_free(query);
+        }
+        subscribers
+    }
+}
+
+impl Drop for SubscriptionQuery {
+    fn drop(&mut self) {
+        unsafe { alsa_ffi::snd_seq_close(self.0) };
+    }
+}
+
+struct PortListing {
+    port: PortDesc,
+    connected_to: Vec<seq::Addr>,
+    connected_from: Vec<seq::Addr>,
+}
+
+impl PortListing {
+    fn capabilities(&self) -> Vec<String> {
+        CAPABILITY_NAMES.iter()
+            .filter(|(flag, _)| self.port.capability.contains(*flag))
+            .map(|(_, name)| name.to_string())
+            .collect()
+    }
+
+    fn types(&self) -> Vec<String> {
+        TYPE_NAMES.iter()
+            .filter(|(flag, _)| self.port.port_type.contains(*flag))
+            .map(|(_, name)| name.to_string())
+            .collect()
+    }
+}
+
+// Clients are listed even without ports, as the ones only sending through others.
+struct ClientListing {
+    client: i32,
+    name: String,
+    ports: Vec<PortListing>,
+}
+
+fn address(addr: seq::Addr) -> String {
+    format!("{}:{}", addr.client, addr.port)
+}
+
+// Every client and its ports, with their subscribers.
+fn all_clients(seq: &seq::Seq) -> Result<Vec<ClientListing>, Box<dyn error::Error>> {
+    let query = SubscriptionQuery::open()?;
+    let mut clients = Vec::new();
+    for client in seq::ClientIter::new(seq) {
+        let mut ports = Vec::new();
+        for port in seq::PortIter::new(seq, client.get_client()) {
+            let addr = seq::Addr { client: port.get_client(), port: port.get_port() };
+            ports.push(PortListing {
+                port: PortDesc::new(seq, addr)?,
+                connected_to: query.subscribers(addr, alsa_ffi::SND_SEQ_QUERY_SUBS_READ),
+                connected_from: query.subscribers(addr, alsa_ffi::SND_SEQ_QUERY_SUBS_WRITE),
+            });
+        }
+        clients.push(ClientListing { client: client.get_client(), name: client.get_name()?.to_string(), ports });
+    }
+    Ok(clients)
+}
+
+fn tree(clients: &[ClientListing]) -> String {
+    let mut tree = String::new();
+    for client in clients {
+        tree.push_str(&format!("{} {}\n", format!("Client {:3}", client.client).bold(), client.name));
+        for listing in &client.ports {
+            let port = &listing.port;
+            tree.push_str(&format!(
+                "  {} {} | {} | {}\n",
+                format!("{:>7}", address(port.addr)).green(),
+                port.port_name,
+                listing.capabilities().join(", ").blue(),
+                listing.types().join(", ").purple()
+            ));
+            for addr in &listing.connected_to {
+                tree.push_str(&format!("          {} {}\n", "Connected to".yellow(), address(*addr)));
+            }
+            for addr in &listing.connected_from {
+                tree.push_str(&format!("          {} {}\n", "Connected from".yellow(), address(*addr)));
+            }
+        }
+    }
+    tree
+}
+
+// A single JSON document, with the clients and their ports inside.
+fn json(clients: &[ClientListing]) -> String {
+    let clients: Vec<String> = clients.iter().map(|client| {
+        let ports: Vec<String> = client.ports.iter().map(|listing| {
+            let connected_to: Vec<String> = listing.connected_to.iter().map(|addr| address(*addr)).collect();
+            let connected_from: Vec<String> = listing.connected_from.iter().map(|addr| address(*addr)).collect();
+            JsonObject::new()
+                .string("address", &address(listing.port.addr))
+                .number("port", listing.port.addr.port)
+                .string("name", &listing.port.port_name)
+                .strings("capabilities", &listing.capabilities())
+                .strings("type", &listing.types())
+                .strings("connected_to", &connected_to)
+                .strings("connected_from", &connected_from)
+                .finish()
+        }).collect();
+        JsonObject::new()
+            .number("client", client.client)
+            .string("name", &client.name)
+            .objects("ports", &ports)
+            .finish()
+    }).collect();
+    JsonObject::new().objects("clients", &clients).finish() + "\n"
+}
+
+fn dot_string(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// A cluster per client and an edge per subscription. Each edge is listed once, from the sender.
+// Clients without ports are a node of their own.
+fn dot(clients: &[ClientListing]) -> String {
+    let mut dot = "digraph alsa_seq {\n    rankdir=LR;\n    node [shape=box];\n".to_string();
+    for client in clients {
+        let label = dot_string(&format!("{} {}", client.client, client.name));
+        if client.ports.is_empty() {
+            dot.push_str(&format!("    {} [label={}, shape=ellipse];\n", dot_string(&client.client.to_string()), label));
+            continue;
+        }
+        dot.push_str(&format!("    subgraph cluster_{} {{\n        label={};\n", client.client, label));
+        for listing in &client.ports {
+            let port = &listing.port;
+            dot.push_str(&format!(
+                "        {} [label={}];\n",
+                dot_string(&address(port.addr)),
+                dot_string(&format!("{} {}", address(port.addr), port.port_name))
+            ));
+        }
+        dot.push_str("    }\n");
+    }
+    for listing in clients.iter().flat_map(|client| &client.ports) {
+        for addr in &listing.connected_to {
+            dot.push_str(&format!("    {} -> {};\n", dot_string(&address(listing.port.addr)), dot_string(&address(*addr))));
+        }
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+/// Prints all clients and ports with their capabilities, type and subscribers.
+pub fn list(format: ListFormat) -> Result<(), Box<dyn error::Error>> {
+    let seq = seq::Seq::open(None, None, false)?;
+    let clients = all_clients(&seq)?;
+    let listing = match format {
+        ListFormat::Tree => tree(&clients),
+        ListFormat::Json => json(&clients),
+        ListFormat::Dot => dot(&clients),
+    };
+    print!("{}", listing);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clients() -> Vec<ClientListing> {
+        let keyboard = PortListing {
+            port: PortDesc {
+                addr: seq::Addr { client: 20, port: 0 },
+                client_name: "Keyboard".to_string(),
+                port_name: "Keys \"A\"".to_string(),
+                capability: seq::READ | seq::SUBS_READ,
+                port_type: seq::MIDI_GENERIC | seq::HARDWARE,
+            },
+            connected_to: vec![seq::Addr { client: 128, port: 0 }],
+            connected_from: vec![],
+        };
+        vec![
+            ClientListing { client: 0, name: "System".to_string(), ports: vec![] },
+            ClientListing { client: 20, name: "Keyboard".to_string(), ports: vec![keyboard] },
+        ]
+    }
+
+    #[test]
+    fn dot_graph() {
+        assert_eq!(
+            dot(&clients()),
+            concat!(
+                "digraph alsa_seq {\n",
+                "    rankdir=LR;\n",
+                "    node [shape=box];\n",
+                "    \"0\" [label=\"0 System\", shape=ellipse];\n",
+                "    subgraph cluster_20 {\n",
+                "        label=\"20 Keyboard\";\n",
+                "        \"20:0\" [label=\"20:0 Keys \\\"A\\\"\"];\n",
+                "    }\n",
+                "    \"20:0\" -> \"128:0\";\n",
+                "}\n",
+            )
+        );
+    }
+
+    #[test]
+    fn dot_without_clients() {
+        assert_eq!(dot(&[]), "digraph alsa_seq {\n    rankdir=LR;\n    node [shape=box];\n}\n");
+    }
+
+    #[test]
+    fn json_document() {
+        assert_eq!(
+            json(&clients()),
+            concat!(
+                r#"{"clients":[{"client":0,"name":"System","ports":[]},"#,
+                r#"{"client":20,"name":"Keyboard","ports":[{"address":"20:0","port":0,"name":"Keys \"A\"","#,
+                r#""capabilities":["read","subs-read"],"type":["midi-generic","hardware"],"#,
+                r#""connected_to":["128:0"],"connected_from":[]}]}]}"#,
+                "\n"
+            )
+        );
+    }
+}
@@ -20,16 +20,41 @@ extern crate lazy_static;
 extern crate clap;
 extern crate alsa;
 extern crate libc;
+extern crate regex;
+
+mod clockstats;
+mod control14;
+mod dashboard;
+mod decode;
+mod event;
+mod expr;
+mod filter;
+mod latency;
+mod list;
+mod mpe;
+mod mtc;
+mod notes;
+mod output;
+mod pianoroll;
+mod ports;
+mod rpn;
+mod send;
+mod smf;
+mod sysex;
+mod thru;
+mod timestamp;
+mod tui;
 
 use alsa::seq;
 use std::error;
 use std::ffi::CString;
 use colored::*;
-use std::collections::HashMap;
-use std::time::Instant;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant, SystemTime};
 use clap::{Arg, App};
 use std::io;
 use std::io::prelude::*;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 lazy_static! {
     static ref CC_MAP: HashMap<u32, String> = build_cc_map();
@@ -37,17 +62,46 @@ lazy_static! {
 }
 const BPM_DAMPING: f64 = 0.03;
 
+// Set from the signal handler on Control C, so the main loop can finish cleanly.
+static EXIT_REQUESTED: AtomicBool = AtomicBool::new(false);
+const CLOCKS_PER_QUARTER_NOTE: i32 = 24;
+const CLOCKS_PER_SONG_POSITION: i32 = 6; // Song Position Pointer counts sixteenth notes
+
 struct MidiMonitor<'a> {
-    start_time: Instant,
-    seq: &'a seq::Seq,
+    start_time: Instant, // When the timestamps queue started
+    both_times: bool, // Whether to show the processing time besides the timestamp
+    time_column: timestamp::TimeColumn, // How to show the event time
+    seq: Option<&'a seq::Seq>, // None when reading from a file
     last_clock: f64,
     average_sec_per_clock: f64,  // Rolling average
     clock_pos: i32, // Song position. once per clock.
-    autoconnect: bool, // Whether to autoconnect to new ports
+    time_signature: (i32, i32), // To show the clock position as bars:beats:ticks
+    clock_stats: Option<clockstats::ClockStats>, // Clock jitter and drift analysis, if enabled
+    autoconnect: Option<ports::AutoconnectRules>, // Which ports to autoconnect to, now and when they appear
+    connect: ports::ConnectRules, // Ports to connect to, now and when they appear
     port: i32,
     port_names: HashMap<seq::Addr, String>,
-    // Whether last line was reused (midi clock) This is used to, if next is not the same type, do new line first.
-    reused_line: bool,
+    // Type of the last line if it was reused (midi clock, MTC) This is used to, if next is not the same type, do new line first.
+    reused_line: Option<seq::EventType>,
+    // Sysex messages may arrive split in several events. Keep the parts until F7 arrives.
+    sysex_buffers: HashMap<seq::Addr, Vec<u8>>,
+    // Selected RPN/NRPN per source and channel, to show data entry as a single parameter change.
+    parameters: HashMap<(seq::Addr, u8), rpn::ParameterState>,
+    pair_14bit: bool, // Whether to join CC 0-31 with their LSB at CC 32-63
+    controls14: HashMap<(seq::Addr, u8), control14::Control14State>,
+    mtc: mtc::MtcDecoder,
+    mpe: bool, // Whether to group MPE per note expression with its note
+    mpe_states: HashMap<seq::Addr, mpe::MpeState>,
+    format: output::OutputFormat,
+    recorder: Option<smf::SmfRecorder>, // Records channel events to a MIDI file, if enabled
+    filter: filter::EventFilter,
+    connected: BTreeMap<seq::Addr, String>, // Ports we receive from
+    announce_connected: bool, // Subscribed to System:Announce, not listed as a connected port
+    tui: Option<tui::Tui>, // Full screen UI, instead of printing the events
+    dashboard: dashboard::Dashboard,
+    piano_roll: pianoroll::PianoRoll,
+    notes: Option<notes::NoteChecker>, // Stuck notes, orphan Note OFF and double Note ON
+    thru: Option<thru::Thru>, // Forwards the received events, if enabled
 }
 
 // List from http://nickfever.com/music/midi-cc-list
@@ -69,9 +123,27 @@ fn build_cc_map() -> HashMap<u32, String> {
         (13, "Effect Controller 2".to_string()),
         // (14, "Undefined".to_string()),
         // (15, "Undefined".to_string()),
-        //(1, "General Purpose".to_string()),
+        (16, "General Purpose Controller 1".to_string()),
+        (17, "General Purpose Controller 2".to_string()),
+        (18, "General Purpose Controller 3".to_string()),
+        (19, "General Purpose Controller 4".to_string()),
         //(1, "Undefined".to_string()),
-        // (1, "Controller 0-31 Least Significant Bit (LSB)".to_string()),
+        (32, "Bank Select LSB".to_string()),
+        (33, "Modulation LSB".to_string()),
+        (34, "Breath Controller LSB".to_string()),
+        (36, "Foot Controller LSB".to_string()),
+        (37, "Portamento Time LSB".to_string()),
+        (38, "Data Entry Least Significant Bit(LSB)".to_string()),
+        (39, "Volume LSB".to_string()),
+        (40, "Balance LSB".to_string()),
+        (42, "Pan LSB".to_string()),
+        (43, "Expression LSB".to_string()),
+        (44, "Effect Controller 1 LSB".to_string()),
+        (45, "Effect Controller 2 LSB".to_string()),
+        (48, "General Purpose Controller 1 LSB".to_string()),
+        (49, "General Purpose Controller 2 LSB".to_string()),
+        (50, "General Purpose Controller 3 LSB".to_string()),
+        (51, "General Purpose Controller 4 LSB".to_string()),
         (64, "Damper Pedal / Sustain Pedal".to_string()),
         (65, "Portamento On/Off Switch".to_string()),
         (66, "Sostenuto On/Off Switch".to_string()),
@@ -252,19 +324,31 @@ fn build_program_map() -> HashMap<u32, String> {
     ].iter().cloned().collect()
 }
 
-fn setup_alsaseq() -> Result<(seq::Seq, i32), Box<error::Error>>{
-    let seq = seq::Seq::open(None, Some(alsa::Direction::Capture), true)?;
+// Also returns when the timestamps queue started, the origin of the event times.
+fn setup_alsaseq() -> Result<(seq::Seq, i32, Instant), Box<dyn error::Error>>{
+    // Duplex, to also send to the thru port
+    let seq = seq::Seq::open(None, None, true)?;
     seq.set_client_name(&CString::new("Terminal MIDI Monitor")?)?;
 
+    // Received events get the real time of this queue on arrival, not when processed.
+    let queue = seq.alloc_named_queue(&CString::new("Terminal MIDI Monitor")?)?;
+
     let mut dinfo = seq::PortInfo::empty()?;
     dinfo.set_capability(seq::WRITE | seq::SUBS_WRITE);
     dinfo.set_type(seq::MIDI_GENERIC | seq::APPLICATION);
     dinfo.set_name(&CString::new("Input")?);
+    dinfo.set_timestamping(true);
+    dinfo.set_timestamp_real(true);
+    dinfo.set_timestamp_queue(queue);
     seq.create_port(&dinfo)?;
 
     let input_port = dinfo.get_port();
 
-    Ok((seq, input_port))
+    seq.control_queue(queue, seq::EventType::Start, 0, None)?;
+    seq.drain_output()?;
+    let start_time = Instant::now();
+
+    Ok((seq, input_port, start_time))
 }
 
 fn note_name(note: u8) -> String {
@@ -287,21 +371,17 @@ fn note_name(note: u8) -> String {
 }
 
 impl<'a> MidiMonitor<'a> {
-    fn get_origin(&mut self, ev: &seq::Event) -> Result<String, Box<error::Error>> {
+    fn get_origin(&mut self, ev: &seq::Event) -> Result<String, Box<dyn error::Error>> {
         let source = ev.get_source();
         self.get_port_name(source)
     }
-    fn get_port_name(&mut self, source: seq::Addr) -> Result<String, Box<error::Error>> {
-        match self.port_names.get(&source) {
-            Some(name) => {
-                return Ok(name.to_string())
-            }
-            None => {
-            }
+    fn get_port_name(&mut self, source: seq::Addr) -> Result<String, Box<dyn error::Error>> {
+        if let Some(name) = self.port_names.get(&source) {
+            return Ok(name.to_string())
         }
 
-        let client_info = match self.seq.get_any_client_info(source.client) {
-            Ok(info) => info,
+        let client_info = match self.seq.map(|seq| seq.get_any_client_info(source.client)) {
+            Some(Ok(info)) => info,
             _ => {
                 return Ok(format!("{}:{}", source.client, source.port));
             }
@@ -309,207 +389,280 @@ impl<'a> MidiMonitor<'a> {
         // Not in cache, calculate
         let origin = format!("{}:{}",
             client_info.get_name()?,
-            self.seq.get_any_port_info(source)?.get_name()?,
+            self.seq()?.get_any_port_info(source)?.get_name()?,
         );
         self.port_names.insert(source, origin);
         let origin = self.port_names.get(&source).ok_or("WTF. I just inserted you.")?;
         Ok(origin.to_string())
     }
+    // Prints a line that the next line of the same type overwrites.
+    fn print_reused_line(&mut self, kind: seq::EventType, line: String) -> Result<(), Box<dyn error::Error>> {
+        if self.reused_line.is_some() && self.reused_line != Some(kind) {
+            println!();
+        }
+        print!("{}\r", line);
+        io::stdout().flush()?;
+        self.reused_line = Some(kind);
+        Ok(())
+    }
+    // Seconds on the clock of the event timestamps.
+    fn now(&self) -> f64 {
+        self.start_time.elapsed().as_secs_f64()
+    }
+    fn clocks_per_beat(&self) -> i32 {
+        CLOCKS_PER_QUARTER_NOTE * 4 / self.time_signature.1
+    }
+    // Clock position as 1 based bar and beat, and clock inside the beat.
+    fn bars_beats_ticks(&self, clock: i32) -> String {
+        let clocks_per_beat = self.clocks_per_beat();
+        let beat = clock.div_euclid(clocks_per_beat);
+        format!(
+            "{:3}:{}:{:02}",
+            beat.div_euclid(self.time_signature.0) + 1,
+            beat.rem_euclid(self.time_signature.0) + 1,
+            clock.rem_euclid(clocks_per_beat),
+        )
+    }
+    // MPE state of the source, if MPE mode is on.
+    fn mpe_state(&mut self, source: seq::Addr) -> Option<&mut mpe::MpeState> {
+        if !self.mpe {
+            return None;
+        }
+        Some(self.mpe_states.entry(source).or_default())
+    }
+    // Parameters with meaning for the monitor itself. Returns extra information to show.
+    fn apply_parameter(&mut self, source: seq::Addr, channel: u8, parameter: &rpn::Parameter) -> Option<String> {
+        if parameter.kind != rpn::ParameterKind::Registered {
+            return None;
+        }
+        let mpe_state = self.mpe_state(source)?;
+        match (parameter.msb, parameter.lsb) {
+            (0, 0) => {
+                mpe_state.set_bend_range(channel, parameter.data_msb() as f64 + parameter.data_lsb() as f64 / 100.0);
+                None
+            }
+            (0, 6) => mpe_state.configure(channel, parameter.data_msb()),
+            _ => None,
+        }
+    }
+    fn restart_clock_stats(&mut self) {
+        if let Some(clock_stats) = self.clock_stats.as_mut() {
+            clock_stats.restart();
+        }
+    }
+    fn own_client(&self) -> Option<i32> {
+        self.seq.and_then(|seq| seq.client_id().ok())
+    }
+    fn is_own_port(&self, addr: seq::Addr) -> bool {
+        match self.seq.map(|seq| seq.client_id()) {
+            Some(Ok(client)) => addr == seq::Addr { client, port: self.port },
+            _ => false,
+        }
+    }
     fn remove_port_name(&mut self, source: seq::Addr) {
         self.port_names.remove(&source);
     }
-    fn autoconnect_all(&mut self) -> Result<(), Box<error::Error>> {
-        let seq = self.seq;
-        for from_info in seq::ClientIter::new(&seq){
-            for from_port in seq::PortIter::new(&seq, from_info.get_client()){
-                if from_port.get_capability().contains(seq::SUBS_READ) && !from_port.get_capability().contains(seq::NO_EXPORT){
-                    let sender = seq::Addr{ client: from_port.get_client(), port: from_port.get_port() };
-                    self.connect_from(sender)?;
-                }
-            }
+    fn seq(&self) -> Result<&'a seq::Seq, Box<dyn error::Error>> {
+        Ok(self.seq.ok_or("No ALSA sequencer when reading from a file")?)
+    }
+    fn autoconnect_all(&mut self) -> Result<(), Box<dyn error::Error>> {
+        self.connect_announce()?;
+        let senders: Vec<seq::Addr> = ports::readable_ports(self.seq()?)
+            .iter()
+            .filter(|port| self.wants_autoconnect(port) && !self.connected.contains_key(&port.addr))
+            .map(|port| port.addr)
+            .collect();
+        for sender in senders {
+            self.connect_from(sender)?;
         }
 
         Ok(())
     }
 
-    fn connect_from(&mut self, sender: seq::Addr) -> Result<(), Box<error::Error>> {
-        let subs = seq::PortSubscribe::empty()?;
-        subs.set_sender(sender);
-        subs.set_dest(seq::Addr{ client: self.seq.client_id()?, port: self.port });
-        self.seq.subscribe_port(&subs)?;
+    // To know when new ports appear
+    fn connect_announce(&mut self) -> Result<(), Box<dyn error::Error>> {
+        if !self.announce_connected {
+            self.connect_from(ports::SYSTEM_ANNOUNCE)?;
+            self.announce_connected = true;
+        }
         Ok(())
     }
-}
 
-fn print_midi_ev(midi_monitor: &mut MidiMonitor, ev: &seq::Event) -> Result<(), Box<error::Error>>{
-    let elapsed = midi_monitor.start_time.elapsed();
-    let elapsed: f64 = elapsed.as_secs() as f64 + elapsed.subsec_millis() as f64 / 1000.0;
-    let mut event;
-    let mut extra_data: String = "".to_string();
-    let origin = midi_monitor.get_origin(&ev)?;
-
-    match ev.get_type() {
-        seq::EventType::Noteon => {
-            let data: seq::EvNote = ev.get_data().ok_or("Error resolving event data")?;
-            event = if data.velocity > 0 {
-                "Note ON ".green()
-            } else {
-                "Note ON ".red()
-            };
-            extra_data = format!(
-                "Channel {:2} | {:<3} ({}) | {}",
-                data.channel.to_string().white().dimmed(),
-                note_name(data.note),
-                data.note,
-                data.velocity
-            );
-        },
-        seq::EventType::Noteoff => {
-            event = "Note OFF".red();
-            let data: seq::EvNote = ev.get_data().ok_or("Error resolving event data")?;
-            extra_data = format!(
-                "Channel {:2} | {:<3} ({}) | {}",
-                data.channel.to_string().white().dimmed(),
-                note_name(data.note),
-                data.note,
-                data.velocity
-            );
-        },
-        seq::EventType::Controller => {
-            let data: seq::EvCtrl = ev.get_data().ok_or("Error resolving event data")?;
-            event = "Controller Change".blue();
-            extra_data = format!(
-                "Channel {:2} | CC {:3} | {:3} | {} ",
-                data.channel,
-                data.param,
-                data.value,
-                CC_MAP.get(&data.param).unwrap_or(&"Unknown".to_string()),
-            );
-        },
-        seq::EventType::Pitchbend => {
-            let data: seq::EvCtrl = ev.get_data().ok_or("Error resolving event data")?;
-            event = "Pitch Bend".purple();
-            extra_data = format!(
-                "Channel {:2} | {} ",
-                data.channel,
-                data.value,
-            );
-        },
-        seq::EventType::Pgmchange => {
-            let data: seq::EvCtrl = ev.get_data().ok_or("Error resolving event data")?;
-            event = "Program Change".purple();
-            extra_data = format!(
-                "{:3} | {}",
-                data.value,
-                PROGRAM_MAP.get(&(data.value as u32)).unwrap_or(&"Unknown".to_string())
-            );
-        },
-        seq::EventType::Chanpress => {
-            let data: seq::EvCtrl = ev.get_data().ok_or("Error resolving event data")?;
-            event = "Channel Pressure".purple();
-            extra_data = format!(
-                "Channel {:2} | {}",
-                data.channel,
-                data.value,
-            );
-        }
-        seq::EventType::Clock => {
-            midi_monitor.clock_pos += 1;
-            midi_monitor.average_sec_per_clock =
-                ((elapsed - midi_monitor.last_clock) * BPM_DAMPING) +
-                midi_monitor.average_sec_per_clock as f64 * (1.0 - BPM_DAMPING);
-            midi_monitor.last_clock = elapsed;
-
-            // I hope RUST simplifies this.. as I prefer clean code.
-            let cs = 1.0 / midi_monitor.average_sec_per_clock;
-            let bs = cs / 24.0; // 24 clocks per beat -> beats per second
-            let bpm = bs * 60.0;
-
-            // Show only once per beat
-            if midi_monitor.clock_pos % 24 == 0 {
-                print!(
-                    "{:10.3} | {:20} | {:>17} | {:>3.1} BPM | Clock Position {}               \r",
-                    elapsed, origin, "Clock".purple(), bpm, midi_monitor.clock_pos
-                );
-                io::stdout().flush()?;
-                midi_monitor.reused_line = true;
-            }
-            return Ok(());
-        }
-        seq::EventType::Start => {
-            event = "Start".purple();
-            midi_monitor.clock_pos = 0;
-        }
-        seq::EventType::Stop => {
-            event = "Stop".purple();
-        }
-        seq::EventType::Continue => {
-            event = "Continue".purple();
+    // Not our own ports, that would loop.
+    fn wants_autoconnect(&self, port: &ports::PortDesc) -> bool {
+        let own_client = self.own_client() == Some(port.addr.client);
+        match self.autoconnect.as_ref() {
+            Some(rules) => !own_client && rules.accepts(port),
+            None => false,
         }
-        seq::EventType::ClientStart => {
-            event = "ClientStart".green();
-            let addr: seq::Addr = ev.get_data().ok_or("Expected address")?;
-            extra_data = format!("{}", midi_monitor.get_port_name(addr)?);
-        }
-        seq::EventType::PortStart => {
-            event = "PortStart".green();
-            let addr: seq::Addr = ev.get_data().ok_or("Expected address")?;
-            if midi_monitor.autoconnect {
-                midi_monitor.connect_from(addr)?;
-            }
-            extra_data = format!("{}", midi_monitor.get_port_name(addr)?);
+    }
+
+    // Connects to the ports of --connect and --connect-regex. Returns the ones not found.
+    fn connect_matching(&mut self) -> Result<Vec<String>, Box<dyn error::Error>> {
+        self.connect_announce()?;
+        let ports = ports::readable_ports(self.seq()?);
+        let senders: Vec<seq::Addr> = ports.iter()
+            .filter(|port| self.connect.matches(port, self.own_client()) && !self.connected.contains_key(&port.addr))
+            .map(|port| port.addr)
+            .collect();
+        for sender in senders {
+            self.connect_from(sender)?;
         }
-        seq::EventType::ClientExit => {
-            event = "ClientExit".red();
-            let addr: seq::Addr = ev.get_data().ok_or("Expected address")?;
-            extra_data = format!("{}", midi_monitor.get_port_name(addr)?);
+        Ok(self.connect.unmatched(&ports, self.own_client()))
+    }
+
+    // Whether to connect to a new port, by the autoconnect rules or one of --connect and --connect-regex.
+    fn wants_port(&self, addr: seq::Addr) -> bool {
+        if self.autoconnect.is_none() && self.connect.is_empty() {
+            return false;
         }
-        seq::EventType::PortExit => {
-            event = "PortExit".red();
-            let addr: seq::Addr = ev.get_data().ok_or("Expected address")?;
-            extra_data = format!("{}", midi_monitor.get_port_name(addr)?);
-            midi_monitor.remove_port_name(addr);
+        match self.seq().and_then(|seq| ports::PortDesc::new(seq, addr)) {
+            Ok(port) => self.wants_autoconnect(&port) || (port.is_readable() && self.connect.matches(&port, self.own_client())),
+            Err(_) => false,
         }
-        seq::EventType::PortSubscribed => {
-            event = "PortSubscribed".green();
-            let conn: seq::Connect = ev.get_data().ok_or("Expected connection")?;
-            extra_data = format!(
-                "{:20} | {:20}",
-                midi_monitor.get_port_name(conn.sender)?,
-                midi_monitor.get_port_name(conn.dest)?
-            );
+    }
+
+    fn connect_from(&mut self, sender: seq::Addr) -> Result<(), Box<dyn error::Error>> {
+        let subs = seq::PortSubscribe::empty()?;
+        subs.set_sender(sender);
+        subs.set_dest(seq::Addr{ client: self.seq()?.client_id()?, port: self.port });
+        self.seq()?.subscribe_port(&subs)?;
+        if sender != ports::SYSTEM_ANNOUNCE {
+            let name = self.get_port_name(sender)?;
+            self.connected.insert(sender, name);
         }
-        seq::EventType::PortUnsubscribed => {
-            event = "PortUnsubscribed".red();
-            let conn: seq::Connect = ev.get_data().ok_or("Expected connection")?;
-            extra_data = format!(
-                "{:20} | {:20}",
-                midi_monitor.get_port_name(conn.sender)?,
-                midi_monitor.get_port_name(conn.dest)?
-            );
+        Ok(())
+    }
+}
+
+// `time` is in seconds since the monitor started, or since the start of the file.
+fn print_midi_ev(midi_monitor: &mut MidiMonitor, ev: &seq::Event, time: f64) -> Result<(), Box<dyn error::Error>>{
+    if let Some(thru) = midi_monitor.thru.as_ref() {
+        thru.forward(midi_monitor.seq()?, ev)?;
+    }
+    if midi_monitor.recorder.is_some() {
+        // Before decoding, as parts of bigger events (RPN selection, 14 bit MSB) are recorded too.
+        let origin = midi_monitor.get_origin(ev)?;
+        if let Some(recorder) = midi_monitor.recorder.as_mut() {
+            recorder.record(time, ev.get_source(), &origin, ev);
         }
-        _ => {
-            event = format!("{:?}", ev).cyan();
+    }
+    if let Some(truncated) = decode::truncated_sysex(midi_monitor, ev, time)? {
+        show_event(midi_monitor, &truncated)?;
+    }
+    let mut ev = match decode::decode_midi_ev(midi_monitor, ev, time)? {
+        Some(ev) => ev,
+        None => return Ok(()),
+    };
+    if midi_monitor.both_times {
+        ev.processed = Some(midi_monitor.now());
+    }
+    if let (Some(recorder), event::MidiEvent::Clock { bpm, beat: true, .. }) = (midi_monitor.recorder.as_mut(), &ev.event) {
+        recorder.tempo(time, *bpm);
+    }
+    // The dashboard has the state of the channels, filtered or not.
+    midi_monitor.dashboard.update(&ev.event);
+    midi_monitor.piano_roll.update(ev.time, &ev.event);
+    let warning = midi_monitor.notes.as_mut().and_then(|notes| notes.check(&ev));
+    show_event(midi_monitor, &ev)?;
+    if let Some(warning) = warning {
+        show_event(midi_monitor, &warning)?;
+    }
+    show_stuck_notes(midi_monitor, time)
+}
+
+fn show_event(midi_monitor: &mut MidiMonitor, ev: &event::DecodedEvent) -> Result<(), Box<dyn error::Error>> {
+    midi_monitor.time_column.observe(ev);
+    let shown = midi_monitor.filter.accept(ev);
+    if let Some(tui) = midi_monitor.tui.as_mut() {
+        // The TUI keeps filtered events too, as filters can be toggled
+        let time = midi_monitor.time_column.format(ev);
+        tui.push(ev, !shown, &time);
+        return Ok(());
+    }
+    if !shown {
+        return Ok(());
+    }
+    match midi_monitor.format {
+        output::OutputFormat::Text => {
+            let time = midi_monitor.time_column.format(ev);
+            output::print_text(midi_monitor, ev, &time)
         }
+        output::OutputFormat::Json => output::print_json(ev),
     }
-    if midi_monitor.reused_line {
-        midi_monitor.reused_line = false;
-        println!();
+}
+
+// Notes held too long are warned even with no more events.
+fn show_stuck_notes(midi_monitor: &mut MidiMonitor, time: f64) -> Result<(), Box<dyn error::Error>> {
+    let stuck = match midi_monitor.notes.as_mut() {
+        Some(notes) => notes.stuck(time),
+        None => return Ok(()),
+    };
+    for ev in &stuck {
+        show_event(midi_monitor, ev)?;
     }
-    println!(
-        "{:10.3} | {:20} | {:>17} | {}                                          ",
-        elapsed,
-        origin,
-        event,
-        extra_data
-    );
+    Ok(())
+}
 
+/// The still held notes, with a header line. Empty if not checking notes.
+fn hanging_notes(midi_monitor: &MidiMonitor) -> Vec<String> {
+    let hanging = match midi_monitor.notes.as_ref() {
+        Some(notes) => notes.hanging(),
+        None => return Vec::new(),
+    };
+    let header = format!("{} {}", "Hanging notes:".yellow(), if hanging.is_empty() { "none".to_string() } else { hanging.len().to_string() });
+    std::iter::once(header).chain(hanging.into_iter().map(|line| format!("  {}", line))).collect()
+}
+
+// Shows the events of a Standard MIDI File, one source per track.
+fn read_midi_file(midi_monitor: &mut MidiMonitor, path: &str) -> Result<(), Box<dyn error::Error>> {
+    let file = smf::read(path)?;
+    let file_name = std::path::Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| path.to_string());
+    for (track, name) in file.track_names.iter().enumerate() {
+        let source = seq::Addr { client: 0, port: track as i32 };
+        midi_monitor.port_names.insert(source, format!("{}:{}", file_name, name));
+    }
+    for smf_event in &file.events {
+        if EXIT_REQUESTED.load(Ordering::SeqCst) {
+            break;
+        }
+        let mut ev = match smf::to_seq_event(&smf_event.data) {
+            Some(ev) => ev,
+            None => continue,
+        };
+        ev.set_source(smf_event.track as i32);
+        print_midi_ev(midi_monitor, &ev, smf_event.time)?;
+    }
     Ok(())
 }
 
+extern "C" fn on_exit_signal(_signal: libc::c_int) {
+    EXIT_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+fn setup_signals() {
+    let handler = on_exit_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
 
-fn main() -> Result<(), Box<error::Error>> {
-    println!("Terminal MIDI Monitor. (C) 2019 Coralbits SL. Licensed under GPL v3.");
+fn parse_time_signature(time_signature: &str) -> Result<(i32, i32), Box<dyn error::Error>> {
+    let mut parts = time_signature.splitn(2, '/');
+    let beats: i32 = parts.next().ok_or("Invalid time signature")?.trim().parse()?;
+    let unit: i32 = parts.next().ok_or("Invalid time signature, expected N/D")?.trim().parse()?;
+    // The beat unit must be a whole number of clocks
+    if beats < 1 || ![1, 2, 4, 8, 16, 32].contains(&unit) {
+        return Err(format!("Invalid time signature {}", time_signature).into());
+    }
+    Ok((beats, unit))
+}
+
+fn main() -> Result<(), Box<dyn error::Error>> {
     let matches = App::new("Terminal MIDI Monitor")
         .version("0.1.0")
         .author("David Moreno <dmoreno@coralbits.com>")
@@ -520,53 +673,593 @@ fn main() -> Result<(), Box<error::Error>> {
                 .long("autoconnect")
                 .help("Autoconnects all outputs to the monitor. Also new clients are automatically connected.")
             )
+        .arg(
+            Arg::with_name("list")
+                .short("l")
+                .long("list")
+                .value_name("FORMAT")
+                .min_values(0)
+                .max_values(1)
+                .possible_values(&["tree", "json", "dot"])
+                .help("Lists all clients and ports with their capabilities, type and subscribers, and exits. As a tree (default), a JSON document or Graphviz DOT.")
+            )
+        .arg(
+            Arg::with_name("autoconnect-exclude")
+                .long("autoconnect-exclude")
+                .value_name("REGEX")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Does not autoconnect to the ports whose client:port name matches this regular expression, as \"Midi Through\" or the outputs of a DAW. Can be repeated. Implies --autoconnect.")
+            )
+        .arg(
+            Arg::with_name("autoconnect-only")
+                .long("autoconnect-only")
+                .value_name("KIND")
+                .possible_values(&["all", "hardware", "software"])
+                .help("Autoconnects only to hardware ports, or only to software ports as Midi Through and applications. Implies --autoconnect.")
+            )
+        .arg(
+            Arg::with_name("14bit")
+                .long("14bit")
+                .help("Pairs controllers 0-31 with their LSB at 32-63 and shows the combined 14 bit value.")
+            )
+        .arg(
+            Arg::with_name("time-signature")
+                .short("t")
+                .long("time-signature")
+                .value_name("N/D")
+                .default_value("4/4")
+                .help("Time signature used to show the clock position as bars:beats:ticks.")
+            )
+        .arg(
+            Arg::with_name("clock-stats")
+                .long("clock-stats")
+                .help("Analyzes the MIDI clock: interval jitter, missing and doubled clocks. Shows a summary per beat and on exit.")
+            )
+        .arg(
+            Arg::with_name("nominal-bpm")
+                .long("nominal-bpm")
+                .value_name("BPM")
+                .help("Expected clock BPM, to measure the drift. Implies --clock-stats.")
+            )
+        .arg(
+            Arg::with_name("mpe")
+                .long("mpe")
+                .help("MPE mode. Follows the MPE zones, and shows pitch bend, slide (CC 74) and pressure of member channels together with their note.")
+            )
+        .arg(
+            Arg::with_name("format")
+                .short("f")
+                .long("format")
+                .value_name("FORMAT")
+                .possible_values(&["text", "json"])
+                .default_value("text")
+                .help("Output format. json writes one JSON object per event and line (JSON Lines), and any other message to stderr.")
+            )
+        .arg(
+            Arg::with_name("time")
+                .long("time")
+                .value_name("MODE")
+                .possible_values(&["absolute", "delta", "source-delta", "wall", "bbt", "smpte"])
+                .default_value("absolute")
+                .help("What the first column shows: seconds since start, seconds since the previous event (delta) or the previous of the same source (source-delta), local time with microseconds (wall), bars:beats:ticks from the clock (bbt), or the last MIDI Time Code (smpte).")
+            )
+        .arg(
+            Arg::with_name("record")
+                .short("r")
+                .long("record")
+                .value_name("FILE")
+                .help("Records all channel events to a Type 1 Standard MIDI File, one track per source, with the tempo from the MIDI clock. Saved on exit.")
+            )
+        .arg(
+            Arg::with_name("connect")
+                .long("connect")
+                .value_name("PORT")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Connects to this port as client:port, by number or name as 20:0, Keystation:0 or \"Midi Through:Midi Through Port-0\". Without port, all the ports of the client. Can be repeated. Reconnects when the port appears again.")
+            )
+        .arg(
+            Arg::with_name("connect-regex")
+                .long("connect-regex")
+                .value_name("REGEX")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Connects to the ports whose client:port name matches this regular expression. Can be repeated. Reconnects when a matching port appears.")
+            )
+        .arg(
+            Arg::with_name("thru")
+                .long("thru")
+                .help("Creates a Thru output port that forwards every received event, with the --thru-* transforms.")
+            )
+        .arg(
+            Arg::with_name("thru-channel")
+                .long("thru-channel")
+                .value_name("FROM:TO")
+                .multiple(true)
+                .number_of_values(1)
+                .requires("thru")
+                .help("Forwards the events of channel FROM to channel TO, 1 based. FROM can be all. Can be repeated.")
+            )
+        .arg(
+            Arg::with_name("thru-transpose")
+                .long("thru-transpose")
+                .value_name("SEMITONES")
+                .allow_hyphen_values(true)
+                .requires("thru")
+                .help("Transposes the forwarded notes. Notes out of range are dropped.")
+            )
+        .arg(
+            Arg::with_name("thru-velocity")
+                .long("thru-velocity")
+                .value_name("CURVE")
+                .requires("thru")
+                .help("Velocity curve for the forwarded Note ON: fixed:N, scale:F or gamma:G (below 1 louder, above 1 softer).")
+            )
+        .arg(
+            Arg::with_name("thru-drop")
+                .long("thru-drop")
+                .value_name("TYPES")
+                .requires("thru")
+                .help("Does not forward these event types, comma separated, as in --ignore.")
+            )
+        .arg(
+            Arg::with_name("send")
+                .long("send")
+                .value_name("MESSAGE")
+                .multiple(true)
+                .number_of_values(1)
+                .requires("to")
+                .help("Sends a message to --to: \"noteon CH NOTE VEL\", \"noteoff CH NOTE\", \"cc CH CC VALUE\", \"program CH PROGRAM\", \"pitchbend CH VALUE\", \"chanpress CH VALUE\" or \"sysex F0 ... F7\". Channels are 1 to 16, notes can be names as C5. Can be repeated. Exits after sending, unless connected to ports to monitor.")
+            )
+        .arg(
+            Arg::with_name("send-cc")
+                .long("send-cc")
+                .value_names(&["CHANNEL", "CC", "VALUE"])
+                .multiple(true)
+                .number_of_values(3)
+                .requires("to")
+                .help("Sends a controller change to --to. Can be repeated.")
+            )
+        .arg(
+            Arg::with_name("panic")
+                .long("panic")
+                .requires("to")
+                .help("Sends All Notes Off, All Sound Off and Reset All Controllers on all channels to --to, before any other message.")
+            )
+        .arg(
+            Arg::with_name("to")
+                .long("to")
+                .value_name("PORT")
+                .help("Port to send the messages to, as client:port by number or name as in --connect.")
+            )
+        .arg(
+            Arg::with_name("latency")
+                .long("latency")
+                .value_name("PORT")
+                .help("Measures the round trip latency: sends probes to this port, as in --to, and times their echo back at the input. Connect the echo with --connect. Shows min, average, max, percentiles and jitter, and exits.")
+            )
+        .arg(
+            Arg::with_name("probes")
+                .long("probes")
+                .value_name("N")
+                .requires("latency")
+                .help("Number of latency probes. Default 100.")
+            )
+        .arg(
+            Arg::with_name("probe-type")
+                .long("probe-type")
+                .value_name("TYPE")
+                .possible_values(&["note", "sysex"])
+                .requires("latency")
+                .help("Latency probe: a note at channel 16, note 0 (default), or a non commercial SysEx.")
+            )
+        .arg(
+            Arg::with_name("probe-interval")
+                .long("probe-interval")
+                .value_name("MS")
+                .requires("latency")
+                .help("Milliseconds between latency probes. Default 100.")
+            )
+        .arg(
+            Arg::with_name("both-times")
+                .long("both-times")
+                .help("Shows when each event was processed besides when it arrived, as the delay in ms, to see the scheduling delay.")
+            )
+        .arg(
+            Arg::with_name("input")
+                .short("i")
+                .long("input")
+                .value_name("FILE")
+                .conflicts_with_all(&["autoconnect", "autoconnect-exclude", "autoconnect-only", "connect", "connect-regex", "thru", "to", "latency", "both-times"])
+                .help("Shows the events of a Standard MIDI File instead of listening to the ALSA sequencer.")
+            )
+        .arg(
+            Arg::with_name("tui")
+                .short("u")
+                .long("tui")
+                .help("Full screen terminal UI, with scrollback, pause, search and a status bar with BPM, clock position and connected ports.")
+            )
+        .arg(
+            Arg::with_name("dashboard")
+                .long("dashboard")
+                .help("Starts the TUI at the dashboard, with the current state of each channel: controllers, pitch bend, pressure, program and held notes. Implies --tui.")
+            )
+        .arg(
+            Arg::with_name("piano-roll")
+                .long("piano-roll")
+                .help("Starts the TUI at the piano roll, a keyboard per channel lighting up the held notes shaded by velocity, with their history below. Implies --tui.")
+                .conflicts_with("dashboard")
+            )
+        .arg(
+            Arg::with_name("check-notes")
+                .long("check-notes")
+                .help("Tracks the held notes per source, channel and note. Warns of stuck notes, Note OFF without Note ON and double Note ON, and lists the hanging notes on exit, or on Enter (h in the TUI).")
+            )
+        .arg(
+            Arg::with_name("stuck-after")
+                .long("stuck-after")
+                .value_name("SECONDS")
+                .help("Warns of notes held longer than this. Default 5 seconds. Implies --check-notes.")
+            )
+        .arg(
+            Arg::with_name("channel")
+                .short("c")
+                .long("channel")
+                .value_name("CHANNELS")
+                .help("Shows only channel events of these channels, 1 to 16, comma separated. Other events are not affected.")
+            )
+        .arg(
+            Arg::with_name("only")
+                .long("only")
+                .value_name("TYPES")
+                .help("Shows only these event types, comma separated. Types are the JSON type names, or the groups note (also polyaftertouch), notecheck, cc (also cc14), rpn (also nrpn and rpnnull), sysex, mtc, transport and connections.")
+            )
+        .arg(
+            Arg::with_name("ignore")
+                .long("ignore")
+                .value_name("TYPES")
+                .help("Does not show these event types, comma separated. Same types as --only.")
+            )
+        .arg(
+            Arg::with_name("source")
+                .short("s")
+                .long("source")
+                .value_name("REGEX")
+                .help("Shows only events from sources whose client:port name matches this regular expression.")
+            )
+        .arg(
+            Arg::with_name("filter")
+                .long("filter")
+                .value_name("EXPRESSION")
+                .help("Shows only events matching the expression, as 'type == cc && param in 1..=7 && value > 100 || (type == noteon && note < C2)'. Fields are named as in the JSON output. Note and controller names can be used as values.")
+            )
         .get_matches();
-    let autoconnect = matches.occurrences_of("autoconnect") > 0;
+    if matches.occurrences_of("list") > 0 {
+        return list::list(list::ListFormat::from_name(matches.value_of("list").unwrap_or("tree"))?);
+    }
+    let format = output::OutputFormat::from_name(matches.value_of("format").unwrap_or("text"))?;
+    let time_mode = timestamp::TimeMode::from_name(matches.value_of("time").unwrap_or("absolute"))?;
+    // In JSON mode stdout only has events, so it can be piped as is.
+    let json = format == output::OutputFormat::Json;
+    let message = |message: &str| {
+        if json {
+            eprintln!("{}", message);
+        } else {
+            println!("{}", message);
+        }
+    };
+    message("Terminal MIDI Monitor. (C) 2019 Coralbits SL. Licensed under GPL v3.");
+    let autoconnect_exclude: Vec<&str> = matches.values_of("autoconnect-exclude").map(|values| values.collect()).unwrap_or_default();
+    let autoconnect = if matches.occurrences_of("autoconnect") > 0
+        || matches.occurrences_of("autoconnect-exclude") > 0
+        || matches.occurrences_of("autoconnect-only") > 0
+    {
+        let kind = ports::PortKind::from_name(matches.value_of("autoconnect-only").unwrap_or("all"))?;
+        Some(ports::AutoconnectRules::new(&autoconnect_exclude, kind)?)
+    } else {
+        None
+    };
+    let pair_14bit = matches.occurrences_of("14bit") > 0;
+    let mpe = matches.occurrences_of("mpe") > 0;
+    let time_signature = parse_time_signature(matches.value_of("time-signature").unwrap_or("4/4"))?;
+    let nominal_bpm = match matches.value_of("nominal-bpm") {
+        Some(bpm) => Some(bpm.parse::<f64>().map_err(|_| format!("Invalid nominal BPM {}", bpm))?),
+        None => None,
+    };
+    let clock_stats = if nominal_bpm.is_some() || matches.occurrences_of("clock-stats") > 0 {
+        Some(clockstats::ClockStats::new(nominal_bpm))
+    } else {
+        None
+    };
+
+    let notes = match matches.value_of("stuck-after") {
+        Some(seconds) => match seconds.parse::<f64>() {
+            Ok(seconds) if seconds > 0.0 => Some(notes::NoteChecker::new(seconds)),
+            _ => return Err(format!("Invalid stuck note time {}", seconds).into()),
+        },
+        None if matches.occurrences_of("check-notes") > 0 => Some(notes::NoteChecker::new(notes::DEFAULT_STUCK_AFTER)),
+        None => None,
+    };
+
+    let filter = filter::EventFilter::new(
+        matches.value_of("channel"),
+        matches.value_of("only"),
+        matches.value_of("ignore"),
+        matches.value_of("source"),
+        matches.value_of("filter"),
+    )?;
+    let recorder = match matches.value_of("record") {
+        Some(path) => Some(smf::SmfRecorder::create(path, time_signature)?),
+        None => None,
+    };
 
-    let (seq, port) = setup_alsaseq()?;
-    let mut input = seq.input();
+    let connect_ports: Vec<&str> = matches.values_of("connect").map(|values| values.collect()).unwrap_or_default();
+    let connect_regexes: Vec<&str> = matches.values_of("connect-regex").map(|values| values.collect()).unwrap_or_default();
+    let connect = ports::ConnectRules::new(&connect_ports, &connect_regexes)?;
 
-    println!("Waiting for connections.");
+    let mut thru = if matches.occurrences_of("thru") > 0 {
+        let channel_maps: Vec<&str> = matches.values_of("thru-channel").map(|values| values.collect()).unwrap_or_default();
+        Some(thru::Thru::new(
+            &channel_maps,
+            matches.value_of("thru-transpose"),
+            matches.value_of("thru-velocity"),
+            matches.value_of("thru-drop"),
+        )?)
+    } else {
+        None
+    };
+
+    let mut outgoing = Vec::new();
+    if matches.occurrences_of("panic") > 0 {
+        outgoing.extend(send::panic_messages());
+    }
+    for message in matches.values_of("send").into_iter().flatten() {
+        outgoing.push(send::parse_message(message)?);
+    }
+    let send_cc: Vec<&str> = matches.values_of("send-cc").map(|values| values.collect()).unwrap_or_default();
+    for cc in send_cc.chunks(3) {
+        outgoing.push(send::cc_message(cc[0], cc[1], cc[2])?);
+    }
+
+    let probe_kind = latency::ProbeKind::from_name(matches.value_of("probe-type").unwrap_or("note"))?;
+    let probes = match matches.value_of("probes").unwrap_or("100").parse::<usize>() {
+        Ok(probes) if probes > 0 => probes,
+        _ => return Err(format!("Invalid number of probes {}", matches.value_of("probes").unwrap_or("")).into()),
+    };
+    let probe_interval = match matches.value_of("probe-interval").unwrap_or("100").parse::<f64>() {
+        Ok(interval) if interval > 0.0 => interval / 1000.0,
+        _ => return Err(format!("Invalid probe interval {}", matches.value_of("probe-interval").unwrap_or("")).into()),
+    };
+    let mut latency_test = None;
 
-    use alsa::PollDescriptors;
-    let seqp = (&seq, Some(alsa::Direction::Capture));
-    let mut fds = Vec::<libc::pollfd>::new();
-    fds.append(&mut seqp.get()?);
+    let input_file = matches.value_of("input");
+    let alsaseq = match input_file {
+        Some(_) => None,
+        None => Some(setup_alsaseq()?),
+    };
+    if let (Some(thru), Some((seq, _port, _start_time))) = (thru.as_mut(), alsaseq.as_ref()) {
+        thru.create_port(seq)?;
+    }
 
+    let start_time = alsaseq.as_ref().map(|(_seq, _port, start_time)| *start_time).unwrap_or_else(Instant::now);
     let mut midi_monitor = MidiMonitor{
-        start_time: Instant::now(),
-        seq: &seq,
+        start_time,
+        // Wall clock at the same time 0 as the event timestamps
+        time_column: timestamp::TimeColumn::new(time_mode, SystemTime::now() - start_time.elapsed()),
+        both_times: matches.occurrences_of("both-times") > 0,
+        seq: alsaseq.as_ref().map(|(seq, _port, _start_time)| seq),
         average_sec_per_clock: (60.0 / 120.0) / 24.0,
         last_clock: 0.0,
         clock_pos: 0,
-        autoconnect: autoconnect,
-        port: port,
+        time_signature,
+        clock_stats,
+        autoconnect,
+        connect,
+        port: alsaseq.as_ref().map(|(_seq, port, _start_time)| *port).unwrap_or(0),
         port_names: HashMap::new(),
-        reused_line: false,
+        reused_line: None,
+        sysex_buffers: HashMap::new(),
+        parameters: HashMap::new(),
+        pair_14bit,
+        controls14: HashMap::new(),
+        mtc: mtc::MtcDecoder::default(),
+        mpe,
+        mpe_states: HashMap::new(),
+        format,
+        recorder,
+        filter,
+        connected: BTreeMap::new(),
+        announce_connected: false,
+        tui: None,
+        dashboard: dashboard::Dashboard::default(),
+        piano_roll: pianoroll::PianoRoll::default(),
+        notes,
+        thru,
     };
 
-    if autoconnect {
-        println!("{}", "Autoconnect ON".yellow());
-        midi_monitor.autoconnect_all()?;
+    setup_signals();
+
+    let view = if matches.occurrences_of("dashboard") > 0 {
+        tui::View::Dashboard
+    } else if matches.occurrences_of("piano-roll") > 0 {
+        tui::View::PianoRoll
+    } else {
+        tui::View::Log
+    };
+    let use_tui = matches.occurrences_of("tui") > 0 || view != tui::View::Log;
+    if use_tui && input_file.is_some() {
+        midi_monitor.tui = Some(tui::Tui::new(view)?);
     }
 
+    match (input_file, alsaseq.as_ref()) {
+        (Some(path), _) => {
+            read_midi_file(&mut midi_monitor, path)?;
+            // Browse the file until quit
+            let mut fds = vec![libc::pollfd { fd: libc::STDIN_FILENO, events: libc::POLLIN, revents: 0 }];
+            while midi_monitor.tui.is_some() && !EXIT_REQUESTED.load(Ordering::SeqCst) {
+                if tui::update(&mut midi_monitor, fds[0].revents & libc::POLLIN != 0)? {
+                    break;
+                }
+                if alsa::poll::poll(&mut fds, 1000).is_err() {
+                    break;
+                }
+            }
+        }
+        (None, Some((seq, _port, _start_time))) => {
+            let mut input = seq.input();
 
-    loop {
-        // FIXME For some events (PortStart,End...) this timeout limits how many to receive per loop.
-        alsa::poll::poll(&mut fds, 1000)?;
-        while input.event_input_pending(true)? != 0 {
-            let ev = input.event_input()?;
+            message("Waiting for connections.");
 
-            match print_midi_ev(&mut midi_monitor, &ev) {
-                Ok(()) => {
+            use alsa::PollDescriptors;
+            let seqp = (seq, Some(alsa::Direction::Capture));
+            let mut fds = Vec::<libc::pollfd>::new();
+            fds.append(&mut seqp.get()?);
 
-                },
-                err => {
-                    println!("{}", format!("ERROR: {:?}",err).red());
+            if midi_monitor.autoconnect.is_some() {
+                message(&"Autoconnect ON".yellow().to_string());
+                midi_monitor.autoconnect_all()?;
+            }
+            if !midi_monitor.connect.is_empty() {
+                for unmatched in midi_monitor.connect_matching()? {
+                    message(&format!("{} {}", "Waiting for".yellow(), unmatched));
                 }
+            }
+
+            let output_port = if matches.is_present("to") || matches.is_present("latency") {
+                send::create_port(seq)?
+            } else {
+                0
             };
+            if let Some(to) = matches.value_of("to") {
+                let dest = ports::find_destination(seq, to)?;
+                send::send(seq, output_port, dest.addr, &mut outgoing)?;
+                message(&format!("{} {} messages to {}", "Sent".yellow(), outgoing.len(), dest.name()));
+                if midi_monitor.autoconnect.is_none() && midi_monitor.connect.is_empty() && !matches.is_present("latency") {
+                    return Ok(());
+                }
+            }
+
+            if let Some(port) = matches.value_of("latency") {
+                let dest = ports::find_destination(seq, port)?;
+                message(&format!("{} {} with {} probes", "Measuring latency to".yellow(), dest.name(), probes));
+                latency_test = Some(latency::LatencyTest::new(probe_kind, probes, probe_interval, dest.addr, output_port));
+            }
+
+            // The TUI also waits for keys, and redraws often for the clock.
+            let (stdin_fd, timeout) = if use_tui {
+                midi_monitor.tui = Some(tui::Tui::new(view)?);
+                fds.push(libc::pollfd { fd: libc::STDIN_FILENO, events: libc::POLLIN, revents: 0 });
+                (Some(fds.len() - 1), 100)
+            } else if midi_monitor.notes.is_some() {
+                // Enter lists the hanging notes
+                fds.push(libc::pollfd { fd: libc::STDIN_FILENO, events: libc::POLLIN, revents: 0 });
+                (Some(fds.len() - 1), 1000)
+            } else {
+                (None, 1000)
+            };
+            // Probes are sent on time
+            let timeout = if latency_test.is_some() { 1 } else { timeout };
+
+            while !EXIT_REQUESTED.load(Ordering::SeqCst) {
+                // FIXME For some events (PortStart,End...) this timeout limits how many to receive per loop.
+                if let Err(err) = alsa::poll::poll(&mut fds, timeout) {
+                    if EXIT_REQUESTED.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    return Err(err.into());
+                }
+                while input.event_input_pending(true)? != 0 {
+                    let ev = input.event_input()?;
+                    // Events not timestamped (as from our own ports) are at processing time
+                    let time = match ev.get_time() {
+                        Some(time) if time > Duration::from_secs(0) => time.as_secs_f64(),
+                        _ => midi_monitor.now(),
+                    };
+
+                    if let Some(latency_test) = latency_test.as_mut().filter(|latency_test| latency_test.is_probe_part(&ev)) {
+                        // Probes are sent at processing time, so the echo is measured on the same clock
+                        if let Some((probe, round_trip)) = latency_test.receive(&ev, midi_monitor.now()) {
+                            let line = format!("{} {:4} | {:.3} ms", "Probe".green(), probe, round_trip * 1000.0);
+                            match midi_monitor.tui.as_mut() {
+                                Some(tui) => tui.message(&line),
+                                None => message(&line),
+                            }
+                        }
+                        continue;
+                    }
+
+                    match print_midi_ev(&mut midi_monitor, &ev, time) {
+                        Ok(()) => {
+
+                        },
+                        err => {
+                            let error = format!("ERROR: {:?}",err).red().to_string();
+                            match midi_monitor.tui.as_mut() {
+                                Some(tui) => tui.message(&error),
+                                None => message(&error),
+                            }
+                        }
+                    };
+                }
+                // Same clock as the note on timestamps
+                let now = midi_monitor.now();
+                show_stuck_notes(&mut midi_monitor, now)?;
+                if let Some(latency_test) = latency_test.as_mut() {
+                    if let Some(probe) = latency_test.tick(seq, now)? {
+                        let line = format!("{} {:4} | Lost", "Probe".red(), probe);
+                        match midi_monitor.tui.as_mut() {
+                            Some(tui) => tui.message(&line),
+                            None => message(&line),
+                        }
+                    }
+                    if latency_test.is_done() {
+                        break;
+                    }
+                }
+                let keys_ready = stdin_fd.map(|fd| fds[fd].revents & libc::POLLIN != 0).unwrap_or(false);
+                if let (Some(fd), true, None) = (stdin_fd, keys_ready, midi_monitor.tui.as_ref()) {
+                    let mut buffer = [0u8; 256];
+                    let len = unsafe { libc::read(libc::STDIN_FILENO, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+                    if len > 0 {
+                        hanging_notes(&midi_monitor).iter().for_each(|line| message(line));
+                    } else {
+                        fds[fd].fd = -1; // Closed stdin, stop polling it
+                    }
+                }
+                if tui::update(&mut midi_monitor, keys_ready)? {
+                    break;
+                }
+            }
         }
-    };
+        (None, None) => {}
+    }
+
+    // Restores the terminal before the summaries
+    midi_monitor.tui = None;
+    if midi_monitor.reused_line.take().is_some() {
+        println!();
+    }
+    if let Some(clock_stats) = midi_monitor.clock_stats.as_ref() {
+        if clock_stats.has_data() {
+            message(&format!("{} {}", "Clock summary:".yellow(), clock_stats.summary()));
+        }
+    }
+    hanging_notes(&midi_monitor).iter().for_each(|line| message(line));
+    if let Some(latency_test) = latency_test.as_ref() {
+        message(&format!("{} {}", "Latency:".yellow(), latency_test.summary()));
+    }
+    if let Some(summary) = midi_monitor.filter.summary() {
+        message(&format!("{} {}", "Filtered out:".yellow(), summary));
+    }
+    if let Some(recorder) = midi_monitor.recorder.as_mut() {
+        recorder.save()?;
+        let path = matches.value_of("record").unwrap_or("");
+        match recorder.track_names().len() {
+            0 => message(&format!("{} {}, no channel events received", "Recorded".yellow(), path)),
+            _ => message(&format!("{} {}: {}", "Recorded".yellow(), path, recorder.track_names().join(", "))),
+        }
+    }
 
     Ok(())
 }
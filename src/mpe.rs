@@ -0,0 +1,223 @@
+/**
+ *  Terminal MIDI Monitor -- Shows MIDI Events on the terminal
+ *  Copyright (C) 2019 David Moreno / Coralbits SL <dmoreno@coralbits.com>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use crate::note_name;
+use std::fmt;
+
+const LOWER_ZONE_MANAGER: u8 = 0;
+const UPPER_ZONE_MANAGER: u8 = 15;
+const MAX_MEMBER_CHANNELS: u8 = 14; // When both zones exist. A single zone can have 15.
+const DEFAULT_MEMBER_BEND_RANGE: f64 = 48.0; // Semitones, from the MPE spec
+const PITCH_BEND_CENTER: f64 = 8192.0;
+
+pub const CC_SLIDE: u32 = 74;
+
+#[derive(Copy, Clone, Debug)]
+struct Zone {
+    members: u8,
+    bend_range: f64,
+}
+
+impl Zone {
+    fn new(members: u8) -> Zone {
+        Zone { members, bend_range: DEFAULT_MEMBER_BEND_RANGE }
+    }
+}
+
+#[derive(Copy, Clone, Default)]
+struct ChannelExpression {
+    note: Option<u8>,
+    bend: i32,
+    slide: Option<i32>,
+    pressure: Option<i32>,
+}
+
+/// Expression of the note on a member channel, after the last change.
+#[derive(Clone, Debug)]
+pub struct NoteExpression {
+    pub note: Option<u8>,
+    pub bend: f64, // Semitones
+    pub slide: Option<i32>,
+    pub pressure: Option<i32>,
+}
+
+impl fmt::Display for NoteExpression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.note {
+            Some(note) => write!(f, "note {:<3} ({})", note_name(note), note)?,
+            None => write!(f, "no note")?,
+        }
+        let slide = self.slide.map(|v| v.to_string()).unwrap_or_else(|| "-".to_string());
+        let pressure = self.pressure.map(|v| v.to_string()).unwrap_or_else(|| "-".to_string());
+        write!(f, " | bend {:+.2} st | slide {:>3} | pressure {:>3}", self.bend, slide, pressure)
+    }
+}
+
+/// MPE zones of one source, and the per note expression on each member channel.
+#[derive(Default)]
+pub struct MpeState {
+    lower: Option<Zone>,
+    upper: Option<Zone>,
+    channels: [ChannelExpression; 16],
+}
+
+impl MpeState {
+    /// MPE Configuration Message (RPN 0,6) on `channel`. Returns the new zone layout if
+    /// it was sent to a zone manager channel.
+    pub fn configure(&mut self, channel: u8, members: u8) -> Option<String> {
+        let members = members.min(MAX_MEMBER_CHANNELS + 1);
+        match channel {
+            LOWER_ZONE_MANAGER => {
+                self.lower = if members > 0 { Some(Zone::new(members)) } else { None };
+                shrink(&mut self.upper, members);
+            }
+            UPPER_ZONE_MANAGER => {
+                self.upper = if members > 0 { Some(Zone::new(members)) } else { None };
+                shrink(&mut self.lower, members);
+            }
+            _ => return None,
+        }
+        Some(self.zones_to_string())
+    }
+
+    fn zones_to_string(&self) -> String {
+        let lower = match self.lower {
+            Some(zone) => format!("Lower zone channels 1-{}", zone.members),
+            None => "No lower zone".to_string(),
+        };
+        let upper = match self.upper {
+            Some(zone) => format!("Upper zone channels {}-14", UPPER_ZONE_MANAGER - zone.members),
+            None => "No upper zone".to_string(),
+        };
+        format!("{} | {}", lower, upper)
+    }
+
+    fn zone_of(&mut self, channel: u8) -> Option<&mut Zone> {
+        match (self.lower.as_mut(), self.upper.as_mut()) {
+            (Some(lower), _) if channel > LOWER_ZONE_MANAGER && channel <= lower.members => Some(lower),
+            (_, Some(upper)) if channel < UPPER_ZONE_MANAGER && channel >= UPPER_ZONE_MANAGER - upper.members => Some(upper),
+            _ => None,
+        }
+    }
+
+    /// Pitch Bend Sensitivity (RPN 0,0) sent to a member channel applies to the whole zone.
+    pub fn set_bend_range(&mut self, channel: u8, semitones: f64) {
+        if let Some(zone) = self.zone_of(channel) {
+            zone.bend_range = semitones;
+        }
+    }
+
+    pub fn note_on(&mut self, channel: u8, note: u8) {
+        if let Some(expression) = self.channels.get_mut(channel as usize) {
+            expression.note = Some(note);
+        }
+    }
+
+    pub fn note_off(&mut self, channel: u8, note: u8) {
+        if let Some(expression) = self.channels.get_mut(channel as usize) {
+            if expression.note == Some(note) {
+                expression.note = None;
+            }
+        }
+    }
+
+    pub fn pitchbend(&mut self, channel: u8, value: i32) -> Option<NoteExpression> {
+        self.expression(channel, |expression| expression.bend = value)
+    }
+
+    pub fn slide(&mut self, channel: u8, value: i32) -> Option<NoteExpression> {
+        self.expression(channel, |expression| expression.slide = Some(value))
+    }
+
+    pub fn pressure(&mut self, channel: u8, value: i32) -> Option<NoteExpression> {
+        self.expression(channel, |expression| expression.pressure = Some(value))
+    }
+
+    // Updates the expression of a member channel, and returns how the note on it is now.
+    fn expression<F: FnOnce(&mut ChannelExpression)>(&mut self, channel: u8, update: F) -> Option<NoteExpression> {
+        let bend_range = self.zone_of(channel)?.bend_range;
+        let expression = self.channels.get_mut(channel as usize)?;
+        update(expression);
+        Some(NoteExpression {
+            note: expression.note,
+            bend: expression.bend as f64 / PITCH_BEND_CENTER * bend_range,
+            slide: expression.slide,
+            pressure: expression.pressure,
+        })
+    }
+}
+
+// Zones can not overlap, the last configured one wins.
+fn shrink(zone: &mut Option<Zone>, other_members: u8) {
+    let available = MAX_MEMBER_CHANNELS.saturating_sub(other_members);
+    let shrunk = match zone {
+        Some(zone) if zone.members > available => Some(available),
+        _ => None,
+    };
+    match shrunk {
+        Some(0) => *zone = None,
+        Some(members) => {
+            if let Some(zone) = zone.as_mut() {
+                zone.members = members;
+            }
+        }
+        None => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bend(state: &mut MpeState, channel: u8, value: i32) -> Option<f64> {
+        state.pitchbend(channel, value).map(|expression| expression.bend)
+    }
+
+    #[test]
+    fn lower_and_upper_zones_split_the_channels() {
+        let mut state = MpeState::default();
+        assert_eq!(state.configure(0, 7).unwrap(), "Lower zone channels 1-7 | No upper zone");
+        assert_eq!(state.configure(15, 7).unwrap(), "Lower zone channels 1-7 | Upper zone channels 8-14");
+        // The last configured zone wins, the other one shrinks.
+        assert_eq!(state.configure(15, 10).unwrap(), "Lower zone channels 1-4 | Upper zone channels 5-14");
+        assert!(bend(&mut state, 4, 0).is_some());
+        assert!(bend(&mut state, 5, 0).is_some());
+        assert!(state.configure(5, 3).is_none());
+    }
+
+    #[test]
+    fn zone_of_no_members_is_removed() {
+        let mut state = MpeState::default();
+        state.configure(0, 7);
+        assert_eq!(state.configure(0, 0).unwrap(), "No lower zone | No upper zone");
+        assert!(bend(&mut state, 1, 100).is_none());
+    }
+
+    #[test]
+    fn bend_range_on_member_channels_only() {
+        let mut state = MpeState::default();
+        state.configure(0, 4);
+        state.note_on(1, 60);
+        // The manager channel has its own bend range, members keep the default 48.
+        state.set_bend_range(0, 2.0);
+        assert_eq!(bend(&mut state, 1, 4096), Some(24.0));
+        // A member channel sets it for the whole zone.
+        state.set_bend_range(2, 12.0);
+        assert_eq!(bend(&mut state, 1, 4096), Some(6.0));
+        assert_eq!(state.pitchbend(1, 4096).unwrap().note, Some(60));
+    }
+}
@@ -0,0 +1,317 @@
+/**
+ *  Terminal MIDI Monitor -- Shows MIDI Events on the terminal
+ *  Copyright (C) 2019 David Moreno / Coralbits SL <dmoreno@coralbits.com>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use std::fmt;
+
+// A full set of 8 quarter frames spans 2 frames
+const FRAMES_PER_QUARTER_FRAME_SET: i64 = 2;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FrameRate {
+    Fps24,
+    Fps25,
+    Fps2997Drop,
+    Fps30,
+}
+
+impl FrameRate {
+    fn from_bits(bits: u8) -> FrameRate {
+        match bits & 0x03 {
+            0 => FrameRate::Fps24,
+            1 => FrameRate::Fps25,
+            2 => FrameRate::Fps2997Drop,
+            _ => FrameRate::Fps30,
+        }
+    }
+
+    /// Frames per second as counted in the timecode, 30 for 29.97 drop frame.
+    pub fn frames_per_second(self) -> i64 {
+        match self {
+            FrameRate::Fps24 => 24,
+            FrameRate::Fps25 => 25,
+            FrameRate::Fps2997Drop | FrameRate::Fps30 => 30,
+        }
+    }
+}
+
+impl fmt::Display for FrameRate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            FrameRate::Fps24 => "24",
+            FrameRate::Fps25 => "25",
+            FrameRate::Fps2997Drop => "29.97df",
+            FrameRate::Fps30 => "30",
+        };
+        write!(f, "{} fps", name)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Timecode {
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
+    pub frames: u8,
+    pub rate: FrameRate,
+}
+
+impl Timecode {
+    /// Frames since 00:00:00:00, skipping the dropped frame numbers on 29.97df.
+    pub fn total_frames(&self) -> i64 {
+        let fps = self.rate.frames_per_second();
+        let total_minutes = self.hours as i64 * 60 + self.minutes as i64;
+        let nominal = (total_minutes * 60 + self.seconds as i64) * fps + self.frames as i64;
+        if self.rate == FrameRate::Fps2997Drop {
+            nominal - 2 * (total_minutes - total_minutes / 10)
+        } else {
+            nominal
+        }
+    }
+
+    pub fn from_total_frames(frames: i64, rate: FrameRate) -> Timecode {
+        let fps = rate.frames_per_second();
+        let mut frames = frames.max(0);
+        if rate == FrameRate::Fps2997Drop {
+            // Add back the dropped frame numbers: 2 per minute, except every 10th minute.
+            let per_10_minutes = 17982;
+            let per_minute = 1798;
+            let tens = frames / per_10_minutes;
+            let rest = frames % per_10_minutes;
+            frames += 18 * tens;
+            if rest > 1 {
+                frames += 2 * ((rest - 2) / per_minute);
+            }
+        }
+        Timecode {
+            hours: ((frames / (fps * 3600)) % 24) as u8,
+            minutes: ((frames / (fps * 60)) % 60) as u8,
+            seconds: ((frames / fps) % 60) as u8,
+            frames: (frames % fps) as u8,
+            rate,
+        }
+    }
+}
+
+impl fmt::Display for Timecode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let separator = if self.rate == FrameRate::Fps2997Drop { ';' } else { ':' };
+        write!(
+            f, "{:02}:{:02}:{:02}{}{:02} @ {}",
+            self.hours, self.minutes, self.seconds, separator, self.frames, self.rate
+        )
+    }
+}
+
+/// Result of feeding a quarter frame or full frame to the decoder.
+pub enum MtcUpdate {
+    /// Still collecting quarter frames.
+    Partial,
+    /// Position is as expected.
+    Running(Timecode),
+    /// The position skipped frames, or some quarter frames were lost: frames and quarter frames.
+    Dropped(Timecode, i64, u32),
+    /// Position went back in time.
+    Backwards(Timecode),
+    /// Full frame message. The position was set.
+    Located(Timecode),
+}
+
+#[derive(Default)]
+pub struct MtcDecoder {
+    pieces: [u8; 8],
+    received: u8, // Bit mask of pieces received in this set
+    last_piece: Option<u8>,
+    lost_pieces: u32, // Quarter frames missing since the last complete set
+    discarded_sets: i64, // Incomplete sets since the last complete set
+    last: Option<Timecode>,
+}
+
+impl MtcDecoder {
+    pub fn quarter_frame(&mut self, value: i32) -> MtcUpdate {
+        let piece = ((value >> 4) & 0x07) as u8;
+        let nibble = (value & 0x0F) as u8;
+
+        let backwards = match self.last_piece {
+            Some(last) if piece == (last + 7) % 8 => true,
+            // A repeated piece is a duplicate, nothing was lost
+            Some(last) if piece != last && piece != (last + 1) % 8 => {
+                self.lost_pieces += ((piece + 7 - last) % 8) as u32;
+                false
+            }
+            _ => false,
+        };
+        self.last_piece = Some(piece);
+        self.pieces[piece as usize] = nibble;
+        self.received |= 1 << piece;
+
+        // Running forward the set is complete on piece 7, running backwards on piece 0.
+        let last_of_set = if backwards { 0 } else { 7 };
+        if piece != last_of_set {
+            return MtcUpdate::Partial;
+        }
+        if self.received != 0xFF {
+            self.received = 0;
+            self.discarded_sets += 1;
+            return MtcUpdate::Partial;
+        }
+        self.received = 0;
+
+        let p = &self.pieces;
+        let assembled = Timecode {
+            frames: p[0] | (p[1] & 0x01) << 4,
+            seconds: p[2] | (p[3] & 0x03) << 4,
+            minutes: p[4] | (p[5] & 0x03) << 4,
+            hours: p[6] | (p[7] & 0x01) << 4,
+            rate: FrameRate::from_bits(p[7] >> 1),
+        };
+        // The set describes the time when piece 0 was sent, which is 2 frames ago.
+        let offset = if backwards { 0 } else { FRAMES_PER_QUARTER_FRAME_SET };
+        let timecode = Timecode::from_total_frames(assembled.total_frames() + offset, assembled.rate);
+
+        let previous = self.last.replace(timecode);
+        let lost_pieces = self.lost_pieces;
+        // Time kept running during the sets thrown away for missing pieces.
+        let expected = FRAMES_PER_QUARTER_FRAME_SET * (1 + self.discarded_sets);
+        self.lost_pieces = 0;
+        self.discarded_sets = 0;
+        let previous = match previous {
+            Some(previous) if previous.rate == timecode.rate => previous,
+            _ => return MtcUpdate::Running(timecode),
+        };
+        let diff = timecode.total_frames() - previous.total_frames();
+        if backwards || diff < 0 {
+            MtcUpdate::Backwards(timecode)
+        } else if lost_pieces > 0 || diff > expected {
+            MtcUpdate::Dropped(timecode, (diff - expected).max(0), lost_pieces)
+        } else {
+            MtcUpdate::Running(timecode)
+        }
+    }
+
+    /// Full frame sysex message: F0 7F <device> 01 01 hh mm ss ff F7
+    pub fn full_frame(&mut self, data: &[u8]) -> Option<MtcUpdate> {
+        if data.len() < 10 || data[0] != 0xF0 || data[1] != 0x7F || data[3] != 0x01 || data[4] != 0x01 {
+            return None;
+        }
+        let timecode = Timecode {
+            hours: data[5] & 0x1F,
+            minutes: data[6] & 0x3F,
+            seconds: data[7] & 0x3F,
+            frames: data[8] & 0x1F,
+            rate: FrameRate::from_bits(data[5] >> 5),
+        };
+        self.last = Some(timecode);
+        self.last_piece = None;
+        self.received = 0;
+        self.lost_pieces = 0;
+        self.discarded_sets = 0;
+        Some(MtcUpdate::Located(timecode))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timecode(hours: u8, minutes: u8, seconds: u8, frames: u8, rate: FrameRate) -> Timecode {
+        Timecode { hours, minutes, seconds, frames, rate }
+    }
+
+    // The 8 quarter frame values for a timecode at 25 fps.
+    fn quarter_frames(tc: Timecode) -> Vec<i32> {
+        let nibbles = [
+            tc.frames & 0x0F, tc.frames >> 4, tc.seconds & 0x0F, tc.seconds >> 4,
+            tc.minutes & 0x0F, tc.minutes >> 4, tc.hours & 0x0F, tc.hours >> 4 | 1 << 1,
+        ];
+        nibbles.iter().enumerate().map(|(piece, nibble)| (piece as i32) << 4 | *nibble as i32).collect()
+    }
+
+    fn feed(decoder: &mut MtcDecoder, values: &[i32]) -> MtcUpdate {
+        let mut update = MtcUpdate::Partial;
+        for value in values {
+            update = decoder.quarter_frame(*value);
+        }
+        update
+    }
+
+    #[test]
+    fn drop_frame_total_frames() {
+        let rate = FrameRate::Fps2997Drop;
+        assert_eq!(timecode(0, 0, 59, 29, rate).total_frames(), 1799);
+        assert_eq!(timecode(0, 1, 0, 2, rate).total_frames(), 1800);
+        assert_eq!(timecode(0, 10, 0, 0, rate).total_frames(), 17982);
+        for frames in &[0, 1799, 1800, 17981, 17982, 107892] {
+            assert_eq!(Timecode::from_total_frames(*frames, rate).total_frames(), *frames);
+        }
+        assert_eq!(Timecode::from_total_frames(1800, rate), timecode(0, 1, 0, 2, rate));
+    }
+
+    #[test]
+    fn quarter_frames_running() {
+        let mut decoder = MtcDecoder::default();
+        let rate = FrameRate::Fps25;
+        match feed(&mut decoder, &quarter_frames(timecode(0, 0, 1, 0, rate))) {
+            MtcUpdate::Running(tc) => assert_eq!(tc, timecode(0, 0, 1, 2, rate)),
+            _ => panic!("Expected running"),
+        }
+        match feed(&mut decoder, &quarter_frames(timecode(0, 0, 1, 2, rate))) {
+            MtcUpdate::Running(tc) => assert_eq!(tc, timecode(0, 0, 1, 4, rate)),
+            _ => panic!("Expected running"),
+        }
+    }
+
+    #[test]
+    fn lost_quarter_frames_are_counted() {
+        let mut decoder = MtcDecoder::default();
+        let rate = FrameRate::Fps25;
+        feed(&mut decoder, &quarter_frames(timecode(0, 0, 1, 0, rate)));
+        let mut incomplete = quarter_frames(timecode(0, 0, 1, 2, rate));
+        incomplete.remove(3);
+        assert!(matches!(feed(&mut decoder, &incomplete), MtcUpdate::Partial));
+        match feed(&mut decoder, &quarter_frames(timecode(0, 0, 1, 4, rate))) {
+            MtcUpdate::Dropped(tc, frames, quarter_frames) => {
+                assert_eq!(tc, timecode(0, 0, 1, 6, rate));
+                assert_eq!(frames, 0);
+                assert_eq!(quarter_frames, 1);
+            }
+            _ => panic!("Expected dropped"),
+        }
+    }
+
+    #[test]
+    fn repeated_quarter_frame_is_not_lost() {
+        let mut decoder = MtcDecoder::default();
+        let rate = FrameRate::Fps25;
+        feed(&mut decoder, &quarter_frames(timecode(0, 0, 1, 0, rate)));
+        let mut repeated = quarter_frames(timecode(0, 0, 1, 2, rate));
+        repeated.insert(3, repeated[3]);
+        match feed(&mut decoder, &repeated) {
+            MtcUpdate::Running(tc) => assert_eq!(tc, timecode(0, 0, 1, 4, rate)),
+            _ => panic!("Expected running"),
+        }
+    }
+
+    #[test]
+    fn full_frame_locates() {
+        let mut decoder = MtcDecoder::default();
+        match decoder.full_frame(&[0xF0, 0x7F, 0x7F, 0x01, 0x01, 0x21, 0x02, 0x03, 0x04, 0xF7]) {
+            Some(MtcUpdate::Located(tc)) => assert_eq!(tc, timecode(1, 2, 3, 4, FrameRate::Fps25)),
+            _ => panic!("Expected located"),
+        }
+        assert!(decoder.full_frame(&[0xF0, 0x43, 0x10, 0xF7]).is_none());
+    }
+}
@@ -0,0 +1,148 @@
+/**
+ *  Terminal MIDI Monitor -- Shows MIDI Events on the terminal
+ *  Copyright (C) 2019 David Moreno / Coralbits SL <dmoreno@coralbits.com>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use alsa::seq;
+use std::collections::BTreeMap;
+use crate::event::{DecodedEvent, MidiEvent, CC_ALL_NOTES_OFF, CC_ALL_SOUND_OFF};
+use crate::note_name;
+
+pub const DEFAULT_STUCK_AFTER: f64 = 5.0;
+
+struct HeldNote {
+    time: f64,
+    velocity: u8,
+    origin: String,
+    stuck: bool, // Already warned
+}
+
+/// Note state per source, channel and note, to warn of stuck notes, Note OFF without
+/// Note ON and double Note ON.
+pub struct NoteChecker {
+    stuck_after: f64, // Seconds
+    held: BTreeMap<(seq::Addr, u8, u8), HeldNote>,
+    now: f64, // Time of the last event or check
+}
+
+impl NoteChecker {
+    pub fn new(stuck_after: f64) -> NoteChecker {
+        NoteChecker { stuck_after, held: BTreeMap::new(), now: 0.0 }
+    }
+
+    /// Updates the held notes with the event. Returns the warning about it, if any.
+    pub fn check(&mut self, ev: &DecodedEvent) -> Option<DecodedEvent> {
+        self.now = self.now.max(ev.time);
+        let warning = match ev.event {
+            MidiEvent::NoteOn { channel, note, velocity } if velocity > 0 => {
+                let held = HeldNote { time: ev.time, velocity, origin: ev.origin.clone(), stuck: false };
+                let previous = self.held.insert((ev.source, channel, note), held)?;
+                MidiEvent::DoubleNoteOn { channel, note, velocity, held: ev.time - previous.time }
+            }
+            MidiEvent::NoteOn { channel, note, .. } | MidiEvent::NoteOff { channel, note, .. } => {
+                match self.held.remove(&(ev.source, channel, note)) {
+                    Some(_) => return None,
+                    None => MidiEvent::OrphanNoteOff { channel, note },
+                }
+            }
+            MidiEvent::Controller { channel, param, .. } if param == CC_ALL_SOUND_OFF || param == CC_ALL_NOTES_OFF => {
+                self.held.retain(|(source, held_channel, _), _| !(*source == ev.source && *held_channel == channel));
+                return None;
+            }
+            _ => return None,
+        };
+        Some(DecodedEvent { time: ev.time, processed: ev.processed, source: ev.source, origin: ev.origin.clone(), event: warning })
+    }
+
+    /// Warnings for the notes held longer than the threshold at this time, once per note.
+    pub fn stuck(&mut self, time: f64) -> Vec<DecodedEvent> {
+        self.now = self.now.max(time);
+        let mut warnings = Vec::new();
+        for ((source, channel, note), held) in self.held.iter_mut() {
+            if held.stuck || time - held.time < self.stuck_after {
+                continue;
+            }
+            held.stuck = true;
+            warnings.push(DecodedEvent {
+                time,
+                processed: None,
+                source: *source,
+                origin: held.origin.clone(),
+                event: MidiEvent::StuckNote { channel: *channel, note: *note, velocity: held.velocity, held: time - held.time },
+            });
+        }
+        warnings
+    }
+
+    /// The notes still held, one line each.
+    pub fn hanging(&self) -> Vec<String> {
+        self.held
+            .iter()
+            .map(|((_, channel, note), held)| {
+                format!(
+                    "{} | Channel {:2} | {:<3} ({}) | {} | Held {:.1} s",
+                    held.origin,
+                    channel,
+                    note_name(*note),
+                    note,
+                    held.velocity,
+                    self.now - held.time
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn double_note_on_and_orphan_note_off() {
+        let mut checker = NoteChecker::new(DEFAULT_STUCK_AFTER);
+        assert!(checker.check(&DecodedEvent::for_test(1.0, 20, MidiEvent::NoteOn { channel: 0, note: 60, velocity: 100 })).is_none());
+        match checker.check(&DecodedEvent::for_test(1.002, 20, MidiEvent::NoteOn { channel: 0, note: 60, velocity: 90 })).map(|ev| ev.event) {
+            Some(MidiEvent::DoubleNoteOn { note: 60, velocity: 90, held, .. }) => assert!((held - 0.002).abs() < 1e-9),
+            _ => panic!("Expected a double Note ON"),
+        }
+        assert!(checker.check(&DecodedEvent::for_test(1.5, 20, MidiEvent::NoteOff { channel: 0, note: 60, velocity: 0 })).is_none());
+        match checker.check(&DecodedEvent::for_test(1.6, 20, MidiEvent::NoteOn { channel: 0, note: 60, velocity: 0 })).map(|ev| ev.event) {
+            Some(MidiEvent::OrphanNoteOff { note: 60, .. }) => {}
+            _ => panic!("Expected an orphan Note OFF"),
+        }
+    }
+
+    #[test]
+    fn stuck_once_by_event_time() {
+        let mut checker = NoteChecker::new(2.0);
+        checker.check(&DecodedEvent::for_test(10.0, 20, MidiEvent::NoteOn { channel: 0, note: 64, velocity: 100 }));
+        assert!(checker.stuck(11.9).is_empty());
+        let stuck = checker.stuck(12.5);
+        match stuck.first().map(|ev| &ev.event) {
+            Some(MidiEvent::StuckNote { note: 64, held, .. }) => assert!((held - 2.5).abs() < 1e-9),
+            _ => panic!("Expected a stuck note"),
+        }
+        assert!(checker.stuck(20.0).is_empty());
+        assert_eq!(checker.hanging().len(), 1);
+    }
+
+    #[test]
+    fn all_notes_off_releases_the_channel() {
+        let mut checker = NoteChecker::new(2.0);
+        checker.check(&DecodedEvent::for_test(0.0, 20, MidiEvent::NoteOn { channel: 3, note: 64, velocity: 100 }));
+        checker.check(&DecodedEvent::for_test(0.1, 20, MidiEvent::Controller { channel: 3, param: CC_ALL_NOTES_OFF, value: 0 }));
+        assert!(checker.hanging().is_empty());
+    }
+}
@@ -0,0 +1,489 @@
+/**
+ *  Terminal MIDI Monitor -- Shows MIDI Events on the terminal
+ *  Copyright (C) 2019 David Moreno / Coralbits SL <dmoreno@coralbits.com>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use alsa::seq;
+use colored::*;
+use std::error;
+use std::fmt::Display;
+use crate::event::{DecodedEvent, MidiEvent};
+use crate::rpn;
+use crate::sysex;
+use crate::{note_name, MidiMonitor, CC_MAP, PROGRAM_MAP};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl OutputFormat {
+    pub fn from_name(name: &str) -> Result<OutputFormat, Box<dyn error::Error>> {
+        match name {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!("Unknown output format {}", name).into()),
+        }
+    }
+}
+
+fn parameter_event_name(parameter: &rpn::Parameter) -> ColoredString {
+    match parameter.kind {
+        rpn::ParameterKind::Registered => "RPN".blue(),
+        rpn::ParameterKind::NonRegistered => "NRPN".blue(),
+    }
+}
+
+fn format_control14(channel: u8, param: u32, value: i32) -> String {
+    format!(
+        "Channel {:2} | CC {:3} | {:5} | {} ",
+        channel,
+        param,
+        value,
+        CC_MAP.get(&param).unwrap_or(&"Unknown".to_string()),
+    )
+}
+
+fn format_note(channel: u8, note: u8, velocity: u8) -> String {
+    format!(
+        "Channel {:2} | {:<3} ({}) | {}",
+        channel.to_string().white().dimmed(),
+        note_name(note),
+        note,
+        velocity
+    )
+}
+
+/// Event name and its data, as shown in the text output.
+pub fn text_columns(ev: &MidiEvent) -> (ColoredString, String) {
+    match ev {
+        MidiEvent::NoteOn { channel, note, velocity } => {
+            let event = if *velocity > 0 {
+                "Note ON ".green()
+            } else {
+                "Note ON ".red()
+            };
+            (event, format_note(*channel, *note, *velocity))
+        }
+        MidiEvent::NoteOff { channel, note, velocity } => ("Note OFF".red(), format_note(*channel, *note, *velocity)),
+        MidiEvent::PolyAftertouch { channel, note, pressure } => {
+            ("Poly Aftertouch".purple(), format_note(*channel, *note, *pressure))
+        }
+        MidiEvent::Controller { channel, param, value } => (
+            "Controller Change".blue(),
+            format!(
+                "Channel {:2} | CC {:3} | {:3} | {} ",
+                channel,
+                param,
+                value,
+                CC_MAP.get(param).unwrap_or(&"Unknown".to_string()),
+            ),
+        ),
+        MidiEvent::Controller14 { channel, param, value } => {
+            ("Controller 14bit".blue(), format_control14(*channel, *param, *value))
+        }
+        MidiEvent::Parameter { channel, parameter, mpe_zones } => {
+            let extra_data = match mpe_zones {
+                Some(mpe_zones) => format!("Channel {:2} | {} | {}", channel, parameter, mpe_zones),
+                None => format!("Channel {:2} | {}", channel, parameter),
+            };
+            (parameter_event_name(parameter), extra_data)
+        }
+        MidiEvent::ParameterNull { channel } => ("RPN".blue(), format!("Channel {:2} | RPN Null", channel)),
+        MidiEvent::PitchBend { channel, value } => ("Pitch Bend".purple(), format!("Channel {:2} | {} ", channel, value)),
+        MidiEvent::ProgramChange { program, .. } => (
+            "Program Change".purple(),
+            format!(
+                "{:3} | {}",
+                program,
+                PROGRAM_MAP.get(&(*program as u32)).unwrap_or(&"Unknown".to_string())
+            ),
+        ),
+        MidiEvent::ChannelPressure { channel, value } => {
+            ("Channel Pressure".purple(), format!("Channel {:2} | {}", channel, value))
+        }
+        MidiEvent::MpeExpression { channel, expression } => {
+            ("MPE".purple(), format!("Channel {:2} | {}", channel, expression))
+        }
+        MidiEvent::StuckNote { channel, note, velocity, held } => (
+            "Stuck Note".red(),
+            format!("{} | Held {:.1} s", format_note(*channel, *note, *velocity), held),
+        ),
+        MidiEvent::OrphanNoteOff { channel, note } => (
+            "Orphan Note OFF".red(),
+            format!("Channel {:2} | {:<3} ({}) | No Note ON", channel, note_name(*note), note),
+        ),
+        MidiEvent::DoubleNoteOn { channel, note, velocity, held } => (
+            "Double Note ON".red(),
+            format!("{} | Already held {:.1} s", format_note(*channel, *note, *velocity), held),
+        ),
+        MidiEvent::SysEx { data } => (
+            "SysEx".yellow(),
+            format!(
+                "{:5} bytes | {}\n{}",
+                data.len(),
+                sysex::describe(data),
+                sysex::hex_dump(data, &" ".repeat(16)),
+            ),
+        ),
+        MidiEvent::SysExTruncated { data } => (
+            "SysEx Truncated".red(),
+            format!(
+                "{:5} bytes | No F7 before the next F0\n{}",
+                data.len(),
+                sysex::hex_dump(data, &" ".repeat(16)),
+            ),
+        ),
+        MidiEvent::MtcFullFrame { timecode } => ("MTC Full Frame".purple(), timecode.to_string()),
+        MidiEvent::Mtc { timecode } => ("MTC".purple(), timecode.to_string()),
+        MidiEvent::MtcDropped { timecode, frames, quarter_frames } => {
+            let mut lost = Vec::new();
+            if *frames > 0 {
+                lost.push(format!("Dropped {} frames", frames));
+            }
+            if *quarter_frames > 0 {
+                lost.push(format!("Lost {} quarter frames", quarter_frames));
+            }
+            ("MTC".red(), format!("{} | {}", timecode, lost.join(" | ")))
+        }
+        MidiEvent::MtcBackwards { timecode } => ("MTC".red(), format!("{} | Running backwards", timecode)),
+        MidiEvent::Clock { bpm, bars_beats_ticks, stats, clock_pos, .. } => {
+            let extra_data = match stats {
+                Some(stats) => format!("{:>3.1} BPM | {} | {}", bpm, bars_beats_ticks, stats),
+                None => format!("{:>3.1} BPM | {} | Clock Position {}", bpm, bars_beats_ticks, clock_pos),
+            };
+            ("Clock".purple(), extra_data)
+        }
+        MidiEvent::SongPosition { value, bars_beats_ticks } => {
+            ("Song Position".purple(), format!("{:5} | {}", value, bars_beats_ticks))
+        }
+        MidiEvent::SongSelect { song } => ("Song Select".purple(), format!("{:3}", song)),
+        MidiEvent::Start => ("Start".purple(), "".to_string()),
+        MidiEvent::Stop => ("Stop".purple(), "".to_string()),
+        MidiEvent::Continue => ("Continue".purple(), "".to_string()),
+        MidiEvent::TuneRequest => ("Tune Request".purple(), "".to_string()),
+        MidiEvent::Reset => ("Reset".red(), "".to_string()),
+        MidiEvent::Sensing => ("Active Sensing".purple(), "".to_string()),
+        MidiEvent::ClientStart { name } => ("ClientStart".green(), name.to_string()),
+        MidiEvent::ClientExit { name } => ("ClientExit".red(), name.to_string()),
+        MidiEvent::PortStart { name } => ("PortStart".green(), name.to_string()),
+        MidiEvent::PortExit { name } => ("PortExit".red(), name.to_string()),
+        MidiEvent::PortSubscribed { sender, dest } => {
+            ("PortSubscribed".green(), format!("{:20} | {:20}", sender, dest))
+        }
+        MidiEvent::PortUnsubscribed { sender, dest } => {
+            ("PortUnsubscribed".red(), format!("{:20} | {:20}", sender, dest))
+        }
+        MidiEvent::Unknown { debug } => (debug.cyan(), "".to_string()),
+    }
+}
+
+/// Colored, column aligned output for humans.
+/// First column, the event time as formatted for --time.
+pub fn print_text(midi_monitor: &mut MidiMonitor, ev: &DecodedEvent, time: &str) -> Result<(), Box<dyn error::Error>> {
+    match &ev.event {
+        MidiEvent::Clock { beat: false, .. } => {
+            // Show only once per beat
+            return Ok(());
+        }
+        MidiEvent::Clock { bpm, bars_beats_ticks, stats: None, .. } => {
+            // Without analysis, the clock keeps a single live line
+            midi_monitor.print_reused_line(seq::EventType::Clock, format!(
+                "{} | {:20} | {:>17} | {:>3.1} BPM | {} {}/{} | Clock Position {}               ",
+                time, ev.origin, "Clock".purple(), bpm, bars_beats_ticks,
+                midi_monitor.time_signature.0, midi_monitor.time_signature.1,
+                midi_monitor.clock_pos
+            ))?;
+            return Ok(());
+        }
+        MidiEvent::Mtc { timecode } => {
+            midi_monitor.print_reused_line(seq::EventType::Qframe, format!(
+                "{} | {:20} | {:>17} | {}               ",
+                time, ev.origin, "MTC".purple(), timecode
+            ))?;
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    let (event, extra_data) = text_columns(&ev.event);
+    if midi_monitor.reused_line.take().is_some() {
+        println!();
+    }
+    println!(
+        "{} | {:20} | {:>17} | {}                                          ",
+        time,
+        ev.origin,
+        event,
+        extra_data
+    );
+    Ok(())
+}
+
+/// Builds a JSON object, one field at a time.
+pub struct JsonObject {
+    json: String,
+}
+
+impl JsonObject {
+    pub fn new() -> JsonObject {
+        JsonObject { json: "{".to_string() }
+    }
+
+    fn key(&mut self, key: &str) {
+        if self.json.len() > 1 {
+            self.json.push(',');
+        }
+        self.json.push_str(&json_string(key));
+        self.json.push(':');
+    }
+
+    pub fn string(&mut self, key: &str, value: &str) -> &mut JsonObject {
+        self.key(key);
+        self.json.push_str(&json_string(value));
+        self
+    }
+
+    pub fn number<T: Display>(&mut self, key: &str, value: T) -> &mut JsonObject {
+        self.key(key);
+        self.json.push_str(&value.to_string());
+        self
+    }
+
+    fn float(&mut self, key: &str, value: f64) -> &mut JsonObject {
+        self.key(key);
+        if value.is_finite() {
+            self.json.push_str(&value.to_string());
+        } else {
+            self.json.push_str("null");
+        }
+        self
+    }
+
+    fn boolean(&mut self, key: &str, value: bool) -> &mut JsonObject {
+        self.number(key, value)
+    }
+
+    fn optional<T: Display>(&mut self, key: &str, value: Option<T>) -> &mut JsonObject {
+        match value {
+            Some(value) => self.number(key, value),
+            None => {
+                self.key(key);
+                self.json.push_str("null");
+                self
+            }
+        }
+    }
+
+    pub fn strings(&mut self, key: &str, values: &[String]) -> &mut JsonObject {
+        self.key(key);
+        let values: Vec<String> = values.iter().map(|value| json_string(value)).collect();
+        self.json.push_str(&format!("[{}]", values.join(",")));
+        self
+    }
+
+    /// Array of objects already in JSON, as from `finish`.
+    pub fn objects(&mut self, key: &str, values: &[String]) -> &mut JsonObject {
+        self.key(key);
+        self.json.push_str(&format!("[{}]", values.join(",")));
+        self
+    }
+
+    pub fn finish(&mut self) -> String {
+        self.json.push('}');
+        self.json.clone()
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn add_note(json: &mut JsonObject, note: u8) {
+    json.number("note", note).string("note_name", &note_name(note));
+}
+
+/// One JSON object per event, one per line.
+pub fn json_line(ev: &DecodedEvent) -> String {
+    let mut json = JsonObject::new();
+    json.float("time", ev.time);
+    if let Some(processed) = ev.processed {
+        json.float("processed", processed);
+    }
+    json.string("source", &format!("{}:{}", ev.source.client, ev.source.port))
+        .string("origin", &ev.origin)
+        .string("type", ev.event.type_name());
+    if let Some(channel) = ev.event.channel() {
+        json.number("channel", channel);
+    }
+    match &ev.event {
+        MidiEvent::NoteOn { note, velocity, .. } | MidiEvent::NoteOff { note, velocity, .. } => {
+            add_note(&mut json, *note);
+            json.number("velocity", velocity);
+        }
+        MidiEvent::PolyAftertouch { note, pressure, .. } => {
+            add_note(&mut json, *note);
+            json.number("pressure", pressure);
+        }
+        MidiEvent::Controller { param, value, .. } | MidiEvent::Controller14 { param, value, .. } => {
+            json.number("param", param)
+                .number("value", value)
+                .string("name", CC_MAP.get(param).map(|name| name.as_str()).unwrap_or("Unknown"));
+        }
+        MidiEvent::Parameter { parameter, mpe_zones, .. } => {
+            json.number("msb", parameter.msb)
+                .number("lsb", parameter.lsb)
+                .number("value", parameter.value)
+                .string("name", parameter.name())
+                .string("value_text", &parameter.value_to_string());
+            if let Some(mpe_zones) = mpe_zones {
+                json.string("mpe_zones", mpe_zones);
+            }
+        }
+        MidiEvent::PitchBend { value, .. } | MidiEvent::ChannelPressure { value, .. } => {
+            json.number("value", value);
+        }
+        MidiEvent::ProgramChange { program, .. } => {
+            json.number("program", program)
+                .string("name", PROGRAM_MAP.get(&(*program as u32)).map(|name| name.as_str()).unwrap_or("Unknown"));
+        }
+        MidiEvent::MpeExpression { expression, .. } => {
+            json.optional("note", expression.note)
+                .float("bend", expression.bend)
+                .optional("slide", expression.slide)
+                .optional("pressure", expression.pressure);
+        }
+        MidiEvent::StuckNote { note, velocity, held, .. } | MidiEvent::DoubleNoteOn { note, velocity, held, .. } => {
+            add_note(&mut json, *note);
+            json.number("velocity", velocity).float("held", *held);
+        }
+        MidiEvent::OrphanNoteOff { note, .. } => {
+            add_note(&mut json, *note);
+        }
+        MidiEvent::SysEx { data } | MidiEvent::SysExTruncated { data } => {
+            let bytes: Vec<String> = data.iter().map(|b| format!("{:02X}", b)).collect();
+            json.number("length", data.len())
+                .string("description", &sysex::describe(data))
+                .string("data", &bytes.join(" "));
+        }
+        MidiEvent::MtcFullFrame { timecode } | MidiEvent::Mtc { timecode } | MidiEvent::MtcBackwards { timecode } => {
+            json.string("timecode", &timecode.to_string());
+        }
+        MidiEvent::MtcDropped { timecode, frames, quarter_frames } => {
+            json.string("timecode", &timecode.to_string())
+                .number("dropped_frames", frames)
+                .number("lost_quarter_frames", quarter_frames);
+        }
+        MidiEvent::Clock { bpm, clock_pos, bars_beats_ticks, beat, stats } => {
+            json.float("bpm", *bpm)
+                .number("clock_pos", clock_pos)
+                .string("position", bars_beats_ticks.trim())
+                .boolean("beat", *beat);
+            if let Some(stats) = stats {
+                json.string("stats", stats);
+            }
+        }
+        MidiEvent::SongPosition { value, bars_beats_ticks } => {
+            json.number("value", value).string("position", bars_beats_ticks.trim());
+        }
+        MidiEvent::SongSelect { song } => {
+            json.number("song", song);
+        }
+        MidiEvent::ClientStart { name }
+        | MidiEvent::ClientExit { name }
+        | MidiEvent::PortStart { name }
+        | MidiEvent::PortExit { name } => {
+            json.string("name", name);
+        }
+        MidiEvent::PortSubscribed { sender, dest } | MidiEvent::PortUnsubscribed { sender, dest } => {
+            json.string("sender", sender).string("dest", dest);
+        }
+        MidiEvent::Unknown { debug } => {
+            json.string("debug", debug);
+        }
+        MidiEvent::ParameterNull { .. }
+        | MidiEvent::Start
+        | MidiEvent::Stop
+        | MidiEvent::Continue
+        | MidiEvent::TuneRequest
+        | MidiEvent::Reset
+        | MidiEvent::Sensing => {}
+    }
+    json.finish()
+}
+
+pub fn print_json(ev: &DecodedEvent) -> Result<(), Box<dyn error::Error>> {
+    println!("{}", json_line(ev));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strings_are_escaped() {
+        let json = JsonObject::new().string("text", "say \"hi\" C:\\dir\n\t\r\x01\x1f ñ").finish();
+        assert_eq!(json, r#"{"text":"say \"hi\" C:\\dir\n\t\r\u0001\u001f ñ"}"#);
+        let json = JsonObject::new().strings("a\"b", &["x\\".to_string(), "\x7f".to_string()]).finish();
+        assert_eq!(json, "{\"a\\\"b\":[\"x\\\\\",\"\x7f\"]}");
+    }
+
+    #[test]
+    fn floats_are_json_numbers() {
+        let json = JsonObject::new()
+            .float("half", 0.5)
+            .float("whole", 2.0)
+            .float("small", 0.001)
+            .float("negative", -1.25)
+            .float("nan", f64::NAN)
+            .float("infinite", f64::INFINITY)
+            .finish();
+        assert_eq!(json, r#"{"half":0.5,"whole":2,"small":0.001,"negative":-1.25,"nan":null,"infinite":null}"#);
+        let third = JsonObject::new().float("third", 1.0 / 3.0).finish();
+        let value: f64 = third.trim_start_matches("{\"third\":").trim_end_matches('}').parse().unwrap();
+        assert_eq!(value, 1.0 / 3.0);
+    }
+
+    #[test]
+    fn event_line() {
+        let ev = DecodedEvent {
+            time: 1.5,
+            processed: None,
+            source: seq::Addr { client: 20, port: 0 },
+            origin: "Keyboard".to_string(),
+            event: MidiEvent::NoteOn { channel: 2, note: 60, velocity: 100 },
+        };
+        assert_eq!(
+            json_line(&ev),
+            r#"{"time":1.5,"source":"20:0","origin":"Keyboard","type":"noteon","channel":2,"note":60,"note_name":"C5","velocity":100}"#
+        );
+    }
+}
@@ -0,0 +1,165 @@
+/**
+ *  Terminal MIDI Monitor -- Shows MIDI Events on the terminal
+ *  Copyright (C) 2019 David Moreno / Coralbits SL <dmoreno@coralbits.com>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use colored::*;
+use std::collections::VecDeque;
+use crate::event::{MidiEvent, CC_ALL_NOTES_OFF, CC_ALL_SOUND_OFF};
+use crate::note_name;
+
+const MAX_HISTORY: usize = 1000;
+const LABEL_WIDTH: usize = 11; // "Channel NN " and the history times
+const PIANO_LOWEST: u8 = 21; // A0 to C8, 88 keys piano
+const PIANO_HIGHEST: u8 = 108;
+const SHADES: [char; 4] = ['░', '▒', '▓', '█'];
+
+type Keys = [u8; 128]; // Velocity of each held note, 0 if not held
+
+// Held notes of all channels after a Note ON or OFF.
+struct Row {
+    time: f64,
+    keys: Keys,
+    changed: u8,
+}
+
+fn is_black(note: u8) -> bool {
+    [1, 3, 6, 8, 10].contains(&(note % 12))
+}
+
+fn shade(velocity: u8) -> char {
+    SHADES[(velocity as usize / 32).min(SHADES.len() - 1)]
+}
+
+/// Keyboard per channel lighting up the held notes, and a history of them.
+pub struct PianoRoll {
+    channels: Vec<Option<Keys>>, // None until the channel has notes
+    history: VecDeque<Row>,
+    seen: Option<(u8, u8)>, // Lowest and highest note
+}
+
+impl Default for PianoRoll {
+    fn default() -> PianoRoll {
+        PianoRoll {
+            channels: vec![None; 16],
+            history: VecDeque::new(),
+            seen: None,
+        }
+    }
+}
+
+impl PianoRoll {
+    pub fn update(&mut self, time: f64, event: &MidiEvent) {
+        let (channel, note, velocity) = match *event {
+            MidiEvent::NoteOn { channel, note, velocity } => (channel, note, velocity),
+            MidiEvent::NoteOff { channel, note, .. } => (channel, note, 0),
+            MidiEvent::Controller { channel, param, .. } if param == CC_ALL_SOUND_OFF || param == CC_ALL_NOTES_OFF => {
+                if let Some(keys) = self.channels.get_mut(channel as usize).and_then(|keys| keys.as_mut()) {
+                    *keys = [0; 128];
+                    self.push_row(time, 0);
+                }
+                return;
+            }
+            _ => return,
+        };
+        let keys = match self.channels.get_mut(channel as usize) {
+            Some(keys) => keys.get_or_insert([0; 128]),
+            None => return,
+        };
+        keys[note as usize & 0x7F] = velocity;
+        self.seen = Some(match self.seen {
+            Some((lowest, highest)) => (lowest.min(note), highest.max(note)),
+            None => (note, note),
+        });
+        self.push_row(time, note);
+    }
+
+    fn push_row(&mut self, time: f64, changed: u8) {
+        let mut keys = [0; 128];
+        for channel in self.channels.iter().flatten() {
+            for (key, velocity) in keys.iter_mut().zip(channel.iter()) {
+                *key = (*key).max(*velocity);
+            }
+        }
+        if self.history.len() >= MAX_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(Row { time, keys, changed });
+    }
+
+    // The piano range plus any note seen out of it. If it does not fit, centered on the seen notes.
+    fn range(&self, width: usize) -> (u8, u8) {
+        let keys = width.saturating_sub(LABEL_WIDTH).clamp(12, 128);
+        let (lowest, highest) = match self.seen {
+            Some((lowest, highest)) => (lowest.min(PIANO_LOWEST), highest.max(PIANO_HIGHEST)),
+            None => (PIANO_LOWEST, PIANO_HIGHEST),
+        };
+        if ((highest - lowest) as usize) < keys {
+            return (lowest, highest);
+        }
+        let center = self.seen.map(|(lowest, highest)| (lowest as usize + highest as usize) / 2).unwrap_or(60);
+        let lowest = center.saturating_sub(keys / 2).min(128 - keys);
+        (lowest as u8, (lowest + keys - 1) as u8)
+    }
+
+    /// Note labels, a keyboard per channel and the history below, newest last. At most `height` lines.
+    pub fn lines(&self, width: usize, height: usize) -> Vec<String> {
+        let (lowest, highest) = self.range(width);
+        let mut labels = vec![' '; LABEL_WIDTH + (highest - lowest) as usize + 1];
+        for note in (lowest..=highest).filter(|note| note % 12 == 0) {
+            let column = LABEL_WIDTH + (note - lowest) as usize;
+            for (i, c) in note_name(note).chars().enumerate() {
+                if let Some(label) = labels.get_mut(column + i) {
+                    *label = c;
+                }
+            }
+        }
+        let mut lines = vec![labels.into_iter().collect::<String>()];
+        for (number, keys) in self.channels.iter().enumerate() {
+            let keys = match keys {
+                Some(keys) => keys,
+                None => continue,
+            };
+            let mut line = format!("Channel {:2} ", number).bold().to_string();
+            for note in lowest..=highest {
+                let velocity = keys[note as usize];
+                let key = if velocity > 0 { shade(velocity).to_string().bright_red() } else { " ".normal() };
+                let key = if is_black(note) { key.on_black() } else { key.on_white() };
+                line.push_str(&key.to_string());
+            }
+            lines.push(line);
+        }
+        if lines.len() == 1 {
+            lines.push("No notes yet".to_string());
+            return lines;
+        }
+        let rows = height.saturating_sub(lines.len());
+        for row in self.history.iter().skip(self.history.len().saturating_sub(rows)) {
+            let mut line = format!("{:10.3} ", row.time);
+            for note in lowest..=highest {
+                let velocity = row.keys[note as usize];
+                if velocity == 0 {
+                    line.push(if note % 12 == 0 { '·' } else { ' ' });
+                } else if note == row.changed {
+                    line.push_str(&shade(velocity).to_string().bright_red().to_string());
+                } else {
+                    line.push(shade(velocity));
+                }
+            }
+            lines.push(line);
+        }
+        lines
+    }
+}
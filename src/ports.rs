@@ -0,0 +1,272 @@
+/**
+ *  Terminal MIDI Monitor -- Shows MIDI Events on the terminal
+ *  Copyright (C) 2019 David Moreno / Coralbits SL <dmoreno@coralbits.com>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use alsa::seq;
+use regex::Regex;
+use std::error;
+
+// Sends the client and port start and exit events.
+pub const SYSTEM_ANNOUNCE: seq::Addr = seq::Addr { client: 0, port: 1 };
+
+/// A port of the sequencer, with the names to match it by.
+pub struct PortDesc {
+    pub addr: seq::Addr,
+    pub client_name: String,
+    pub port_name: String,
+    pub capability: seq::PortCap,
+    pub port_type: seq::PortType,
+}
+
+impl PortDesc {
+    pub fn new(seq: &seq::Seq, addr: seq::Addr) -> Result<PortDesc, Box<dyn error::Error>> {
+        let port_info = seq.get_any_port_info(addr)?;
+        Ok(PortDesc {
+            addr,
+            client_name: seq.get_any_client_info(addr.client)?.get_name()?.to_string(),
+            port_name: port_info.get_name()?.to_string(),
+            capability: port_info.get_capability(),
+            port_type: port_info.get_type(),
+        })
+    }
+
+    /// As `get_port_name`, client and port names.
+    pub fn name(&self) -> String {
+        format!("{}:{}", self.client_name, self.port_name)
+    }
+
+    pub fn is_readable(&self) -> bool {
+        self.capability.contains(seq::SUBS_READ) && !self.capability.contains(seq::NO_EXPORT)
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.capability.contains(seq::SUBS_WRITE) && !self.capability.contains(seq::NO_EXPORT)
+    }
+
+    // Midi Through and applications are not hardware.
+    pub fn is_hardware(&self) -> bool {
+        self.port_type.contains(seq::HARDWARE)
+    }
+}
+
+fn all_ports(seq: &seq::Seq) -> Vec<PortDesc> {
+    let mut ports = Vec::new();
+    for client in seq::ClientIter::new(seq) {
+        for port in seq::PortIter::new(seq, client.get_client()) {
+            let addr = seq::Addr { client: port.get_client(), port: port.get_port() };
+            if let Ok(port) = PortDesc::new(seq, addr) {
+                ports.push(port);
+            }
+        }
+    }
+    ports
+}
+
+/// All the ports that can be connected from.
+pub fn readable_ports(seq: &seq::Seq) -> Vec<PortDesc> {
+    all_ports(seq).into_iter().filter(|port| port.is_readable()).collect()
+}
+
+/// The first port that can be connected to matching the client:port spec, as in --connect.
+pub fn find_destination(seq: &seq::Seq, spec: &str) -> Result<PortDesc, Box<dyn error::Error>> {
+    let spec = PortSpec::parse(spec);
+    all_ports(seq)
+        .into_iter()
+        .find(|port| port.is_writable() && spec.matches(port))
+        .ok_or_else(|| format!("No port to send to matches {}", spec.spec).into())
+}
+
+/// Which ports to autoconnect, by their type.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PortKind {
+    All,
+    Hardware,
+    Software,
+}
+
+impl PortKind {
+    pub fn from_name(name: &str) -> Result<PortKind, Box<dyn error::Error>> {
+        match name {
+            "all" => Ok(PortKind::All),
+            "hardware" => Ok(PortKind::Hardware),
+            "software" => Ok(PortKind::Software),
+            _ => Err(format!("Unknown port kind {}", name).into()),
+        }
+    }
+}
+
+/// Which ports --autoconnect connects to. Never the System client ports, as the timer,
+/// though the announcements are always connected to know of new ports.
+pub struct AutoconnectRules {
+    exclude: Vec<Regex>,
+    kind: PortKind,
+}
+
+impl AutoconnectRules {
+    pub fn new(exclude: &[&str], kind: PortKind) -> Result<AutoconnectRules, Box<dyn error::Error>> {
+        let exclude = exclude
+            .iter()
+            .map(|regex| Regex::new(regex).map_err(|err| format!("Invalid autoconnect exclude regex: {}", err)))
+            .collect::<Result<Vec<Regex>, String>>()?;
+        Ok(AutoconnectRules { exclude, kind })
+    }
+
+    pub fn accepts(&self, port: &PortDesc) -> bool {
+        if !port.is_readable() || port.addr.client == SYSTEM_ANNOUNCE.client {
+            return false;
+        }
+        let kind = match self.kind {
+            PortKind::All => true,
+            PortKind::Hardware => port.is_hardware(),
+            PortKind::Software => !port.is_hardware(),
+        };
+        let name = port.name();
+        kind && !self.exclude.iter().any(|regex| regex.is_match(&name))
+    }
+}
+
+// Client or port, by number or name.
+enum Part {
+    Number(i32),
+    Name(String),
+}
+
+impl Part {
+    fn parse(part: &str) -> Part {
+        match part.trim().parse() {
+            Ok(number) => Part::Number(number),
+            Err(_) => Part::Name(part.trim().to_string()),
+        }
+    }
+
+    fn matches(&self, number: i32, name: &str) -> bool {
+        match self {
+            Part::Number(n) => *n == number,
+            Part::Name(n) => n.eq_ignore_ascii_case(name),
+        }
+    }
+}
+
+// "client:port" as aconnect, with numbers or names. Without port, all the ports of the client.
+struct PortSpec {
+    spec: String,
+    client: Part,
+    port: Option<Part>,
+}
+
+impl PortSpec {
+    fn parse(spec: &str) -> PortSpec {
+        let (client, port) = match spec.find(':') {
+            Some(colon) => (&spec[..colon], Some(Part::parse(&spec[colon + 1..]))),
+            None => (spec, None),
+        };
+        PortSpec { spec: spec.to_string(), client: Part::parse(client), port }
+    }
+
+    fn matches(&self, port: &PortDesc) -> bool {
+        self.client.matches(port.addr.client, &port.client_name)
+            && self.port.as_ref().map(|part| part.matches(port.addr.port, &port.port_name)).unwrap_or(true)
+    }
+}
+
+/// Ports to connect to from the command line, by address, name or regex of the name.
+pub struct ConnectRules {
+    specs: Vec<PortSpec>,
+    regexes: Vec<Regex>,
+}
+
+impl ConnectRules {
+    pub fn new(specs: &[&str], regexes: &[&str]) -> Result<ConnectRules, Box<dyn error::Error>> {
+        let regexes = regexes
+            .iter()
+            .map(|regex| Regex::new(regex).map_err(|err| format!("Invalid connect regex: {}", err)))
+            .collect::<Result<Vec<Regex>, String>>()?;
+        Ok(ConnectRules { specs: specs.iter().map(|spec| PortSpec::parse(spec)).collect(), regexes })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.specs.is_empty() && self.regexes.is_empty()
+    }
+
+    // Regexes skip the ports of our own client, as the thru port, that would loop.
+    fn regex_matches(regex: &Regex, port: &PortDesc, own_client: Option<i32>) -> bool {
+        Some(port.addr.client) != own_client && regex.is_match(&port.name())
+    }
+
+    /// Whether to connect to the port. Only an explicit client:port connects to our own client.
+    pub fn matches(&self, port: &PortDesc, own_client: Option<i32>) -> bool {
+        self.specs.iter().any(|spec| spec.matches(port))
+            || self.regexes.iter().any(|regex| ConnectRules::regex_matches(regex, port, own_client))
+    }
+
+    /// The rules no port matches, to tell they are waiting.
+    pub fn unmatched(&self, ports: &[PortDesc], own_client: Option<i32>) -> Vec<String> {
+        let specs = self.specs.iter()
+            .filter(|spec| !ports.iter().any(|port| spec.matches(port)))
+            .map(|spec| spec.spec.clone());
+        let regexes = self.regexes.iter()
+            .filter(|regex| !ports.iter().any(|port| ConnectRules::regex_matches(regex, port, own_client)))
+            .map(|regex| format!("/{}/", regex));
+        specs.chain(regexes).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn port(client: i32, port: i32, client_name: &str, port_name: &str, port_type: seq::PortType) -> PortDesc {
+        PortDesc {
+            addr: seq::Addr { client, port },
+            client_name: client_name.to_string(),
+            port_name: port_name.to_string(),
+            capability: seq::READ | seq::SUBS_READ,
+            port_type,
+        }
+    }
+
+    #[test]
+    fn connect_by_address_name_and_regex() {
+        let rules = ConnectRules::new(&["20:0", "Keystation"], &["(?i)synth"]).unwrap();
+        assert!(rules.matches(&port(20, 0, "Any", "Any", seq::MIDI_GENERIC), None));
+        assert!(rules.matches(&port(24, 1, "keystation", "Port 2", seq::MIDI_GENERIC), None));
+        assert!(rules.matches(&port(128, 0, "SoftSynth", "In", seq::MIDI_GENERIC), None));
+        assert!(!rules.matches(&port(20, 1, "Any", "Any", seq::MIDI_GENERIC), None));
+    }
+
+    #[test]
+    fn regex_skips_own_client() {
+        let rules = ConnectRules::new(&[], &["Thru|Through"]).unwrap();
+        let own = port(129, 1, "Terminal MIDI Monitor", "Thru", seq::MIDI_GENERIC);
+        assert!(!rules.matches(&own, Some(129)));
+        assert_eq!(rules.unmatched(&[own], Some(129)), vec!["/Thru|Through/"]);
+        assert!(rules.matches(&port(14, 0, "Midi Through", "Midi Through Port-0", seq::MIDI_GENERIC), Some(129)));
+        // Explicit address still connects
+        let rules = ConnectRules::new(&["129:1"], &[]).unwrap();
+        assert!(rules.matches(&port(129, 1, "Terminal MIDI Monitor", "Thru", seq::MIDI_GENERIC), Some(129)));
+    }
+
+    #[test]
+    fn autoconnect_kinds_and_exclude() {
+        let hardware = port(24, 0, "USB Keyboard", "MIDI 1", seq::MIDI_GENERIC | seq::HARDWARE);
+        let software = port(128, 0, "Sequencer", "Out", seq::MIDI_GENERIC | seq::APPLICATION);
+        let system = port(0, 0, "System", "Timer", seq::PortType::empty());
+        let rules = AutoconnectRules::new(&["Sequencer"], PortKind::All).unwrap();
+        assert!(rules.accepts(&hardware) && !rules.accepts(&software) && !rules.accepts(&system));
+        let rules = AutoconnectRules::new(&[], PortKind::Software).unwrap();
+        assert!(!rules.accepts(&hardware) && rules.accepts(&software));
+    }
+}
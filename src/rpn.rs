@@ -0,0 +1,257 @@
+/**
+ *  Terminal MIDI Monitor -- Shows MIDI Events on the terminal
+ *  Copyright (C) 2019 David Moreno / Coralbits SL <dmoreno@coralbits.com>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use std::fmt;
+
+const CC_DATA_ENTRY_MSB: u32 = 6;
+const CC_DATA_ENTRY_LSB: u32 = 38;
+const CC_DATA_INCREMENT: u32 = 96;
+const CC_DATA_DECREMENT: u32 = 97;
+const CC_NRPN_LSB: u32 = 98;
+const CC_NRPN_MSB: u32 = 99;
+const CC_RPN_LSB: u32 = 100;
+const CC_RPN_MSB: u32 = 101;
+
+const RPN_NULL: u8 = 127;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ParameterKind {
+    Registered,
+    NonRegistered,
+}
+
+/// A fully resolved parameter change, ready to show.
+#[derive(Clone, Debug)]
+pub struct Parameter {
+    pub kind: ParameterKind,
+    pub msb: u8,
+    pub lsb: u8,
+    pub value: u16, // 14 bit, MSB << 7 | LSB
+}
+
+/// What a controller change meant for the RPN/NRPN state of its channel.
+pub enum ParameterUpdate {
+    /// Not part of a RPN/NRPN sequence. Show it as a plain controller.
+    None,
+    /// Parameter number selection. Nothing to show until data arrives.
+    Selected,
+    /// The RPN Null was selected, no parameter is active anymore.
+    Deselected,
+    /// New value for the currently selected parameter.
+    Value(Parameter),
+}
+
+/// RPN/NRPN selection and value for one channel of one source.
+#[derive(Default)]
+pub struct ParameterState {
+    kind: Option<ParameterKind>,
+    msb: Option<u8>,
+    lsb: Option<u8>,
+    value: Option<u16>,
+}
+
+impl ParameterState {
+    pub fn update(&mut self, param: u32, value: i32) -> ParameterUpdate {
+        let value = (value & 0x7F) as u8;
+        match param {
+            CC_RPN_MSB | CC_RPN_LSB | CC_NRPN_MSB | CC_NRPN_LSB => {
+                let kind = if param == CC_RPN_MSB || param == CC_RPN_LSB {
+                    ParameterKind::Registered
+                } else {
+                    ParameterKind::NonRegistered
+                };
+                if self.kind != Some(kind) {
+                    self.kind = Some(kind);
+                    self.msb = None;
+                    self.lsb = None;
+                }
+                if param == CC_RPN_MSB || param == CC_NRPN_MSB {
+                    self.msb = Some(value);
+                } else {
+                    self.lsb = Some(value);
+                }
+                self.value = None;
+                if kind == ParameterKind::Registered && self.msb == Some(RPN_NULL) && self.lsb == Some(RPN_NULL) {
+                    self.kind = None;
+                    return ParameterUpdate::Deselected;
+                }
+                ParameterUpdate::Selected
+            }
+            CC_DATA_ENTRY_MSB | CC_DATA_ENTRY_LSB | CC_DATA_INCREMENT | CC_DATA_DECREMENT => {
+                let (kind, msb, lsb) = match (self.kind, self.msb, self.lsb) {
+                    (Some(kind), Some(msb), Some(lsb)) => (kind, msb, lsb),
+                    _ => return ParameterUpdate::None,
+                };
+                let current = self.value.unwrap_or(0);
+                let new_value = match param {
+                    CC_DATA_ENTRY_MSB => (value as u16) << 7,
+                    CC_DATA_ENTRY_LSB => (current & !0x7F) | value as u16,
+                    CC_DATA_INCREMENT => (current + 1).min(0x3FFF),
+                    _ => current.saturating_sub(1),
+                };
+                self.value = Some(new_value);
+                ParameterUpdate::Value(Parameter { kind, msb, lsb, value: new_value })
+            }
+            _ => ParameterUpdate::None,
+        }
+    }
+}
+
+impl Parameter {
+    /// From ALSA's own Regparam/Nonregparam events, where both the parameter number
+    /// and the value come already as 14 bit numbers.
+    pub fn from_14bit(kind: ParameterKind, param: u32, value: i32) -> Parameter {
+        Parameter {
+            kind,
+            msb: ((param >> 7) & 0x7F) as u8,
+            lsb: (param & 0x7F) as u8,
+            value: (value & 0x3FFF) as u16,
+        }
+    }
+
+    pub fn data_msb(&self) -> u8 {
+        (self.value >> 7) as u8
+    }
+
+    pub fn data_lsb(&self) -> u8 {
+        (self.value & 0x7F) as u8
+    }
+
+    pub fn name(&self) -> &'static str {
+        if self.kind == ParameterKind::NonRegistered {
+            return "";
+        }
+        match (self.msb, self.lsb) {
+            (0, 0) => "Pitch Bend Sensitivity",
+            (0, 1) => "Channel Fine Tuning",
+            (0, 2) => "Channel Coarse Tuning",
+            (0, 3) => "Tuning Program Change",
+            (0, 4) => "Tuning Bank Select",
+            (0, 5) => "Modulation Depth Range",
+            (0, 6) => "MPE Configuration",
+            (0x3D, 0) => "Azimuth Angle",
+            (0x3D, 1) => "Elevation Angle",
+            (0x3D, 2) => "Gain",
+            (0x3D, 3) => "Distance Ratio",
+            (0x3D, 4) => "Maximum Distance",
+            (0x3D, 5) => "Gain at Maximum Distance",
+            (0x3D, 6) => "Reference Distance Ratio",
+            (0x3D, 7) => "Pan Spread Angle",
+            (0x3D, 8) => "Roll Angle",
+            _ => "Unknown",
+        }
+    }
+
+    /// Value in the units of the parameter, if known.
+    pub fn value_to_string(&self) -> String {
+        if self.kind == ParameterKind::Registered {
+            match (self.msb, self.lsb) {
+                (0, 0) => {
+                    return if self.data_lsb() == 0 {
+                        format!("{} semitones", self.data_msb())
+                    } else {
+                        format!("{} semitones {} cents", self.data_msb(), self.data_lsb())
+                    };
+                }
+                (0, 1) => {
+                    let cents = (self.value as f64 - 8192.0) * 100.0 / 8192.0;
+                    return format!("{:+.1} cents", cents);
+                }
+                (0, 2) => return format!("{:+} semitones", self.data_msb() as i32 - 64),
+                (0, 6) => return format!("{} member channels", self.data_msb()),
+                _ => {}
+            }
+        }
+        format!("{} (MSB {}, LSB {})", self.value, self.data_msb(), self.data_lsb())
+    }
+}
+
+impl fmt::Display for Parameter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            ParameterKind::Registered => "RPN",
+            ParameterKind::NonRegistered => "NRPN",
+        };
+        let name = self.name();
+        if name.is_empty() {
+            write!(f, "{} {},{} = {}", kind, self.msb, self.lsb, self.value_to_string())
+        } else {
+            write!(f, "{} {},{} ({}) = {}", kind, self.msb, self.lsb, name, self.value_to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(update: ParameterUpdate) -> Option<Parameter> {
+        match update {
+            ParameterUpdate::Value(parameter) => Some(parameter),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn pitch_bend_sensitivity() {
+        let mut state = ParameterState::default();
+        assert!(matches!(state.update(CC_RPN_MSB, 0), ParameterUpdate::Selected));
+        assert!(matches!(state.update(CC_RPN_LSB, 0), ParameterUpdate::Selected));
+        let parameter = value(state.update(CC_DATA_ENTRY_MSB, 12)).unwrap();
+        assert_eq!(parameter.to_string(), "RPN 0,0 (Pitch Bend Sensitivity) = 12 semitones");
+        let parameter = value(state.update(CC_DATA_ENTRY_LSB, 50)).unwrap();
+        assert_eq!(parameter.to_string(), "RPN 0,0 (Pitch Bend Sensitivity) = 12 semitones 50 cents");
+    }
+
+    #[test]
+    fn nrpn_increment_and_decrement() {
+        let mut state = ParameterState::default();
+        state.update(CC_NRPN_MSB, 1);
+        state.update(CC_NRPN_LSB, 8);
+        assert_eq!(value(state.update(CC_DATA_ENTRY_MSB, 64)).unwrap().value, 64 << 7);
+        assert_eq!(value(state.update(CC_DATA_INCREMENT, 0)).unwrap().value, (64 << 7) + 1);
+        let parameter = value(state.update(CC_DATA_DECREMENT, 0)).unwrap();
+        assert_eq!(parameter.kind, ParameterKind::NonRegistered);
+        assert_eq!(parameter.to_string(), "NRPN 1,8 = 8192 (MSB 64, LSB 0)");
+    }
+
+    #[test]
+    fn data_entry_needs_a_selection() {
+        let mut state = ParameterState::default();
+        assert!(matches!(state.update(CC_DATA_ENTRY_MSB, 1), ParameterUpdate::None));
+        state.update(CC_RPN_MSB, 0);
+        // Only the MSB is selected
+        assert!(matches!(state.update(CC_DATA_ENTRY_MSB, 1), ParameterUpdate::None));
+        assert!(matches!(state.update(7, 100), ParameterUpdate::None));
+    }
+
+    #[test]
+    fn rpn_null_deselects() {
+        let mut state = ParameterState::default();
+        state.update(CC_RPN_MSB, 0);
+        state.update(CC_RPN_LSB, 1);
+        state.update(CC_RPN_MSB, RPN_NULL as i32);
+        assert!(matches!(state.update(CC_RPN_LSB, RPN_NULL as i32), ParameterUpdate::Deselected));
+        assert!(matches!(state.update(CC_DATA_ENTRY_MSB, 1), ParameterUpdate::None));
+    }
+
+    #[test]
+    fn from_alsa_14bit() {
+        let parameter = Parameter::from_14bit(ParameterKind::Registered, 1, 8192);
+        assert_eq!(parameter.to_string(), "RPN 0,1 (Channel Fine Tuning) = +0.0 cents");
+    }
+}
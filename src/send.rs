@@ -0,0 +1,210 @@
+/**
+ *  Terminal MIDI Monitor -- Shows MIDI Events on the terminal
+ *  Copyright (C) 2019 David Moreno / Coralbits SL <dmoreno@coralbits.com>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use alsa::seq;
+use std::error;
+use std::ffi::CString;
+use crate::event::{CC_ALL_NOTES_OFF, CC_ALL_SOUND_OFF, CC_RESET_ALL_CONTROLLERS};
+use crate::expr::{cc_number, note_number};
+
+// Number in the range, or a name with its own parser.
+fn parse_value(word: &str, what: &str, range: (i32, i32), name: fn(&str) -> Option<f64>) -> Result<i32, Box<dyn error::Error>> {
+    let value = word.parse::<i32>().ok().or_else(|| name(word).map(|value| value as i32));
+    match value {
+        Some(value) if value >= range.0 && value <= range.1 => Ok(value),
+        _ => Err(format!("Invalid {} {}, must be {} to {}", what, word, range.0, range.1).into()),
+    }
+}
+
+fn no_name(_: &str) -> Option<f64> {
+    None
+}
+
+// 1 based, as in --channel.
+fn parse_channel(word: &str) -> Result<u8, Box<dyn error::Error>> {
+    Ok(parse_value(word, "channel", (1, 16), no_name)? as u8 - 1)
+}
+
+fn note_event(kind: seq::EventType, channel: u8, note: i32, velocity: i32) -> seq::Event<'static> {
+    seq::Event::new(kind, &seq::EvNote { channel, note: note as u8, velocity: velocity as u8, off_velocity: 0, duration: 0 })
+}
+
+fn ctrl_event(kind: seq::EventType, channel: u8, param: u32, value: i32) -> seq::Event<'static> {
+    seq::Event::new(kind, &seq::EvCtrl { channel, param, value })
+}
+
+/// Event for a message as "noteon 1 C5 100", "noteoff 1 60", "cc 1 7 127", "program 1 5",
+/// "pitchbend 1 -8192", "chanpress 1 64" or "sysex F0 7E 7F 06 01 F7". Channels are 1 based.
+pub fn parse_message(message: &str) -> Result<seq::Event<'static>, Box<dyn error::Error>> {
+    let words: Vec<&str> = message.split_whitespace().collect();
+    let kind = words.first().map(|kind| kind.to_lowercase()).unwrap_or_default();
+    let args = &words[words.len().min(1)..];
+    let expect = |count: usize, usage: &str| -> Result<(), Box<dyn error::Error>> {
+        if args.len() == count {
+            Ok(())
+        } else {
+            Err(format!("Invalid message \"{}\", use {}", message, usage).into())
+        }
+    };
+    let event = match kind.as_str() {
+        "noteon" => {
+            expect(3, "noteon CHANNEL NOTE VELOCITY")?;
+            let note = parse_value(args[1], "note", (0, 127), note_number)?;
+            note_event(seq::EventType::Noteon, parse_channel(args[0])?, note, parse_value(args[2], "velocity", (0, 127), no_name)?)
+        }
+        "noteoff" => {
+            let velocity = match args.len() {
+                2 => 0,
+                _ => {
+                    expect(3, "noteoff CHANNEL NOTE [VELOCITY]")?;
+                    parse_value(args[2], "velocity", (0, 127), no_name)?
+                }
+            };
+            let note = parse_value(args[1], "note", (0, 127), note_number)?;
+            note_event(seq::EventType::Noteoff, parse_channel(args[0])?, note, velocity)
+        }
+        "cc" => {
+            expect(3, "cc CHANNEL CONTROLLER VALUE")?;
+            let param = parse_value(args[1], "controller", (0, 127), cc_number)? as u32;
+            ctrl_event(seq::EventType::Controller, parse_channel(args[0])?, param, parse_value(args[2], "value", (0, 127), no_name)?)
+        }
+        "program" => {
+            expect(2, "program CHANNEL PROGRAM")?;
+            ctrl_event(seq::EventType::Pgmchange, parse_channel(args[0])?, 0, parse_value(args[1], "program", (0, 127), no_name)?)
+        }
+        "pitchbend" => {
+            expect(2, "pitchbend CHANNEL VALUE")?;
+            ctrl_event(seq::EventType::Pitchbend, parse_channel(args[0])?, 0, parse_value(args[1], "pitch bend", (-8192, 8191), no_name)?)
+        }
+        "chanpress" => {
+            expect(2, "chanpress CHANNEL VALUE")?;
+            ctrl_event(seq::EventType::Chanpress, parse_channel(args[0])?, 0, parse_value(args[1], "pressure", (0, 127), no_name)?)
+        }
+        "sysex" => {
+            let data = args
+                .iter()
+                .map(|byte| u8::from_str_radix(byte, 16).map_err(|_| format!("Invalid sysex byte {}, use hex as F0", byte)))
+                .collect::<Result<Vec<u8>, String>>()?;
+            if data.first() != Some(&0xF0) || data.last() != Some(&0xF7) {
+                return Err(format!("Invalid message \"{}\", sysex must start with F0 and end with F7", message).into());
+            }
+            seq::Event::new_ext(seq::EventType::Sysex, data)
+        }
+        _ => {
+            return Err(format!(
+                "Unknown message \"{}\". Valid messages are noteon, noteoff, cc, program, pitchbend, chanpress and sysex",
+                message
+            ).into())
+        }
+    };
+    Ok(event)
+}
+
+/// Controller change from --send-cc, 1 based channel.
+pub fn cc_message(channel: &str, param: &str, value: &str) -> Result<seq::Event<'static>, Box<dyn error::Error>> {
+    let param = parse_value(param, "controller", (0, 127), cc_number)? as u32;
+    Ok(ctrl_event(seq::EventType::Controller, parse_channel(channel)?, param, parse_value(value, "value", (0, 127), no_name)?))
+}
+
+/// All Notes Off, All Sound Off and Reset All Controllers on all channels.
+pub fn panic_messages() -> Vec<seq::Event<'static>> {
+    let mut events = Vec::new();
+    for channel in 0..16 {
+        for param in &[CC_ALL_NOTES_OFF, CC_ALL_SOUND_OFF, CC_RESET_ALL_CONTROLLERS] {
+            events.push(ctrl_event(seq::EventType::Controller, channel, *param, 0));
+        }
+    }
+    events
+}
+
+/// Output port to send from.
+pub fn create_port(seq: &seq::Seq) -> Result<i32, Box<dyn error::Error>> {
+    let mut pinfo = seq::PortInfo::empty()?;
+    pinfo.set_capability(seq::READ | seq::SUBS_READ);
+    pinfo.set_type(seq::MIDI_GENERIC | seq::APPLICATION);
+    pinfo.set_name(&CString::new("Output")?);
+    seq.create_port(&pinfo)?;
+    Ok(pinfo.get_port())
+}
+
+/// Sends the events right away, from the port to the destination.
+pub fn send(seq: &seq::Seq, port: i32, dest: seq::Addr, events: &mut [seq::Event<'static>]) -> Result<(), Box<dyn error::Error>> {
+    for event in events.iter_mut() {
+        event.set_source(port);
+        event.set_dest(dest);
+        event.set_direct();
+        seq.event_output_direct(event)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(message: &str) -> (seq::EventType, u8, u8, u8) {
+        let ev = parse_message(message).unwrap();
+        let note: seq::EvNote = ev.get_data().unwrap();
+        (ev.get_type(), note.channel, note.note, note.velocity)
+    }
+
+    fn ctrl(message: &str) -> (seq::EventType, u8, u32, i32) {
+        let ev = parse_message(message).unwrap();
+        let ctrl: seq::EvCtrl = ev.get_data().unwrap();
+        (ev.get_type(), ctrl.channel, ctrl.param, ctrl.value)
+    }
+
+    #[test]
+    fn notes() {
+        assert_eq!(note("noteon 1 C5 100"), (seq::EventType::Noteon, 0, 60, 100));
+        assert_eq!(note("NoteOn 16 61 1"), (seq::EventType::Noteon, 15, 61, 1));
+        assert_eq!(note("noteoff 2 60"), (seq::EventType::Noteoff, 1, 60, 0));
+        assert_eq!(note("noteoff 2 60 64"), (seq::EventType::Noteoff, 1, 60, 64));
+    }
+
+    #[test]
+    fn controllers() {
+        assert_eq!(ctrl("cc 1 Volume 127"), (seq::EventType::Controller, 0, 7, 127));
+        assert_eq!(ctrl("program 10 5"), (seq::EventType::Pgmchange, 9, 0, 5));
+        assert_eq!(ctrl("pitchbend 1 -8192"), (seq::EventType::Pitchbend, 0, 0, -8192));
+        assert_eq!(ctrl("chanpress 3 64"), (seq::EventType::Chanpress, 2, 0, 64));
+        let ev = cc_message("1", "64", "127").unwrap();
+        let data: seq::EvCtrl = ev.get_data().unwrap();
+        assert_eq!((data.param, data.value), (64, 127));
+    }
+
+    #[test]
+    fn sysex() {
+        let ev = parse_message("sysex F0 7E 7F 06 01 F7").unwrap();
+        assert_eq!(ev.get_type(), seq::EventType::Sysex);
+        assert_eq!(ev.get_ext().unwrap(), &[0xF0, 0x7E, 0x7F, 0x06, 0x01, 0xF7]);
+        assert!(parse_message("sysex 7E 7F F7").is_err());
+        assert!(parse_message("sysex F0 XX F7").is_err());
+    }
+
+    #[test]
+    fn invalid_messages() {
+        for message in &["noteon 0 60 100", "noteon 1 60", "noteon 1 128 100", "cc 1 7 128", "pitchbend 1 8192", "bogus 1", ""] {
+            assert!(parse_message(message).is_err(), "{} should fail", message);
+        }
+    }
+
+    #[test]
+    fn panic_on_all_channels() {
+        assert_eq!(panic_messages().len(), 16 * 3);
+    }
+}
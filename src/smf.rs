@@ -0,0 +1,528 @@
+/**
+ *  Terminal MIDI Monitor -- Shows MIDI Events on the terminal
+ *  Copyright (C) 2019 David Moreno / Coralbits SL <dmoreno@coralbits.com>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use alsa::seq;
+use std::collections::HashMap;
+use std::error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+pub const TICKS_PER_QUARTER_NOTE: u16 = 480;
+const DEFAULT_BPM: f64 = 120.0;
+// Measured BPM wobbles a bit, only tempo changes bigger than this go to the file.
+const TEMPO_CHANGE_THRESHOLD: f64 = 0.5;
+
+const META_TRACK_NAME: u8 = 0x03;
+const META_END_OF_TRACK: u8 = 0x2F;
+const META_TEMPO: u8 = 0x51;
+const META_TIME_SIGNATURE: u8 = 0x58;
+
+/// Raw MIDI bytes of a channel event, as one or more messages. None if it is not a channel event.
+pub fn channel_messages(ev: &seq::Event) -> Option<Vec<Vec<u8>>> {
+    let note = |status: u8| -> Option<Vec<Vec<u8>>> {
+        let data: seq::EvNote = ev.get_data()?;
+        Some(vec![vec![status | (data.channel & 0x0F), data.note & 0x7F, data.velocity & 0x7F]])
+    };
+    let ctrl = || -> Option<seq::EvCtrl> { ev.get_data() };
+    match ev.get_type() {
+        seq::EventType::Noteon => note(0x90),
+        seq::EventType::Noteoff => note(0x80),
+        seq::EventType::Keypress => note(0xA0),
+        seq::EventType::Controller => {
+            let data = ctrl()?;
+            Some(vec![control_change(data.channel, data.param, data.value)])
+        }
+        seq::EventType::Control14 => {
+            let data = ctrl()?;
+            if data.param < 32 {
+                Some(vec![
+                    control_change(data.channel, data.param, data.value >> 7),
+                    control_change(data.channel, data.param + 32, data.value),
+                ])
+            } else {
+                Some(vec![control_change(data.channel, data.param, data.value)])
+            }
+        }
+        seq::EventType::Regparam | seq::EventType::Nonregparam => {
+            let data = ctrl()?;
+            let (msb_cc, lsb_cc) = if ev.get_type() == seq::EventType::Regparam { (101, 100) } else { (99, 98) };
+            Some(vec![
+                control_change(data.channel, msb_cc, data.param as i32 >> 7),
+                control_change(data.channel, lsb_cc, data.param as i32),
+                control_change(data.channel, 6, data.value >> 7),
+                control_change(data.channel, 38, data.value),
+            ])
+        }
+        seq::EventType::Pgmchange => {
+            let data = ctrl()?;
+            Some(vec![vec![0xC0 | (data.channel & 0x0F), (data.value & 0x7F) as u8]])
+        }
+        seq::EventType::Chanpress => {
+            let data = ctrl()?;
+            Some(vec![vec![0xD0 | (data.channel & 0x0F), (data.value & 0x7F) as u8]])
+        }
+        seq::EventType::Pitchbend => {
+            let data = ctrl()?;
+            let value = (data.value + 8192).clamp(0, 16383);
+            Some(vec![vec![0xE0 | (data.channel & 0x0F), (value & 0x7F) as u8, (value >> 7) as u8]])
+        }
+        _ => None,
+    }
+}
+
+fn control_change(channel: u8, param: u32, value: i32) -> Vec<u8> {
+    vec![0xB0 | (channel & 0x0F), (param & 0x7F) as u8, (value & 0x7F) as u8]
+}
+
+// Absolute tick and the MIDI or meta event bytes
+type TrackEvent = (u64, Vec<u8>);
+
+struct Track {
+    name: String,
+    events: Vec<TrackEvent>,
+}
+
+impl Track {
+    fn new(name: &str) -> Track {
+        let mut track = Track { name: name.to_string(), events: Vec::new() };
+        track.meta(0, META_TRACK_NAME, name.as_bytes());
+        track
+    }
+
+    fn meta(&mut self, tick: u64, kind: u8, data: &[u8]) {
+        let mut event = vec![0xFF, kind];
+        write_variable_length(&mut event, data.len() as u32);
+        event.extend_from_slice(data);
+        self.events.push((tick, event));
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut last_tick = 0;
+        for (tick, event) in &self.events {
+            write_variable_length(&mut data, tick.saturating_sub(last_tick) as u32);
+            data.extend_from_slice(event);
+            last_tick = last_tick.max(*tick);
+        }
+        // End of track
+        data.extend_from_slice(&[0x00, 0xFF, META_END_OF_TRACK, 0x00]);
+        data
+    }
+}
+
+fn write_variable_length(data: &mut Vec<u8>, value: u32) {
+    let mut bytes = vec![(value & 0x7F) as u8];
+    let mut value = value >> 7;
+    while value > 0 {
+        bytes.push(0x80 | (value & 0x7F) as u8);
+        value >>= 7;
+    }
+    bytes.reverse();
+    data.extend_from_slice(&bytes);
+}
+
+/// Records channel events into a Type 1 Standard MIDI File. Track 0 has the tempo map,
+/// and there is one more track per source port.
+pub struct SmfRecorder {
+    file: File,
+    tempo_track: Track,
+    tracks: Vec<Track>,
+    track_of_source: HashMap<seq::Addr, usize>,
+    // Last tempo change, as seconds, tick and BPM, to convert from time to ticks.
+    tempo_time: f64,
+    tempo_tick: u64,
+    bpm: f64,
+    saved: bool, // Whether the file has all the recorded events
+}
+
+impl SmfRecorder {
+    /// Creates the file now, so a wrong path fails before starting to record.
+    pub fn create(path: &str, time_signature: (i32, i32)) -> Result<SmfRecorder, Box<dyn error::Error>> {
+        let file = File::create(path).map_err(|err| format!("Can not create {}: {}", path, err))?;
+        let mut tempo_track = Track::new("Terminal MIDI Monitor");
+        let unit_log2 = (time_signature.1 as u32).trailing_zeros() as u8;
+        tempo_track.meta(0, META_TIME_SIGNATURE, &[time_signature.0 as u8, unit_log2, 24, 8]);
+        let mut recorder = SmfRecorder {
+            file,
+            tempo_track,
+            tracks: Vec::new(),
+            track_of_source: HashMap::new(),
+            tempo_time: 0.0,
+            tempo_tick: 0,
+            bpm: DEFAULT_BPM,
+            saved: false,
+        };
+        recorder.add_tempo(0);
+        Ok(recorder)
+    }
+
+    fn tick_at(&self, time: f64) -> u64 {
+        let beats = (time - self.tempo_time).max(0.0) * self.bpm / 60.0;
+        self.tempo_tick + (beats * TICKS_PER_QUARTER_NOTE as f64).round() as u64
+    }
+
+    fn add_tempo(&mut self, tick: u64) {
+        let usec_per_quarter = (60_000_000.0 / self.bpm).round() as u32;
+        self.tempo_track.meta(tick, META_TEMPO, &usec_per_quarter.to_be_bytes()[1..]);
+    }
+
+    /// Tempo measured from the MIDI clock at `time` seconds.
+    pub fn tempo(&mut self, time: f64, bpm: f64) {
+        if !bpm.is_finite() || bpm <= 0.0 || (bpm - self.bpm).abs() < TEMPO_CHANGE_THRESHOLD {
+            return;
+        }
+        let tick = self.tick_at(time);
+        self.tempo_time = time;
+        self.tempo_tick = tick;
+        self.bpm = bpm;
+        self.add_tempo(tick);
+        self.saved = false;
+    }
+
+    /// Records the event if it is a channel event, in the track of its source.
+    pub fn record(&mut self, time: f64, source: seq::Addr, origin: &str, ev: &seq::Event) {
+        let messages = match channel_messages(ev) {
+            Some(messages) => messages,
+            None => return,
+        };
+        let tick = self.tick_at(time);
+        let tracks = &mut self.tracks;
+        let index = *self.track_of_source.entry(source).or_insert_with(|| {
+            tracks.push(Track::new(origin));
+            tracks.len() - 1
+        });
+        for message in messages {
+            self.tracks[index].events.push((tick, message));
+        }
+        self.saved = false;
+    }
+
+    /// Writes the whole file. Can be called more than once, each time rewrites it.
+    pub fn save(&mut self) -> Result<(), Box<dyn error::Error>> {
+        use std::io::Seek;
+        self.file.set_len(0)?;
+        self.file.seek(std::io::SeekFrom::Start(0))?;
+        let mut out = BufWriter::new(&self.file);
+        out.write_all(b"MThd")?;
+        out.write_all(&6u32.to_be_bytes())?;
+        out.write_all(&1u16.to_be_bytes())?; // Type 1, several tracks played together
+        out.write_all(&(self.tracks.len() as u16 + 1).to_be_bytes())?;
+        out.write_all(&TICKS_PER_QUARTER_NOTE.to_be_bytes())?;
+        for track in std::iter::once(&self.tempo_track).chain(self.tracks.iter()) {
+            let data = track.to_bytes();
+            out.write_all(b"MTrk")?;
+            out.write_all(&(data.len() as u32).to_be_bytes())?;
+            out.write_all(&data)?;
+        }
+        out.flush()?;
+        self.saved = true;
+        Ok(())
+    }
+
+    /// Names of the recorded tracks, one per source.
+    pub fn track_names(&self) -> Vec<&str> {
+        self.tracks.iter().map(|track| track.name.as_str()).collect()
+    }
+}
+
+// Any exit, also on errors, leaves a valid file.
+impl Drop for SmfRecorder {
+    fn drop(&mut self) {
+        if self.saved {
+            return;
+        }
+        if let Err(err) = self.save() {
+            eprintln!("Can not save the recording: {}", err);
+        }
+    }
+}
+
+/// An event read from a Standard MIDI File, at `time` seconds from the start.
+pub struct SmfEvent {
+    pub time: f64,
+    pub track: usize,
+    pub data: Vec<u8>, // A complete channel message or sysex
+}
+
+/// Contents of a Standard MIDI File, with all the tracks merged in time order.
+pub struct SmfFile {
+    pub track_names: Vec<String>,
+    pub events: Vec<SmfEvent>,
+}
+
+struct ByteReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], Box<dyn error::Error>> {
+        if self.pos + len > self.data.len() {
+            return Err("Truncated MIDI file".into());
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, Box<dyn error::Error>> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, Box<dyn error::Error>> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn variable_length(&mut self) -> Result<u32, Box<dyn error::Error>> {
+        let mut value: u32 = 0;
+        for _ in 0..4 {
+            let byte = self.byte()?;
+            value = (value << 7) | (byte & 0x7F) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("Invalid variable length quantity in MIDI file".into())
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+}
+
+// Events of a track: channel messages, sysex and meta events (starting with FF).
+fn read_track(data: &[u8]) -> Result<Vec<TrackEvent>, Box<dyn error::Error>> {
+    let mut reader = ByteReader { data, pos: 0 };
+    let mut events = Vec::new();
+    let mut tick: u64 = 0;
+    let mut running_status: Option<u8> = None;
+    while !reader.at_end() {
+        tick += reader.variable_length()? as u64;
+        let mut first = reader.byte()?;
+        let status = if first & 0x80 != 0 {
+            first
+        } else {
+            let status = running_status.ok_or("MIDI file data without status")?;
+            reader.pos -= 1;
+            first = status;
+            status
+        };
+        match status {
+            0xFF => {
+                let kind = reader.byte()?;
+                let len = reader.variable_length()? as usize;
+                let mut event = vec![0xFF, kind];
+                event.extend_from_slice(reader.bytes(len)?);
+                running_status = None;
+                if kind == META_END_OF_TRACK {
+                    break;
+                }
+                events.push((tick, event));
+            }
+            0xF0 | 0xF7 => {
+                let len = reader.variable_length()? as usize;
+                // F7 continues a sysex split in several events, the data goes as is.
+                let mut event = if status == 0xF0 { vec![0xF0] } else { Vec::new() };
+                event.extend_from_slice(reader.bytes(len)?);
+                running_status = None;
+                events.push((tick, event));
+            }
+            _ => {
+                let len = match status {
+                    0xC0..=0xDF | 0xF1 | 0xF3 => 1,
+                    0xF6..=0xFE => 0,
+                    _ => 2,
+                };
+                let mut event = vec![first];
+                event.extend_from_slice(reader.bytes(len)?);
+                running_status = if status < 0xF0 { Some(status) } else { None };
+                events.push((tick, event));
+            }
+        }
+    }
+    Ok(events)
+}
+
+/// Reads a Standard MIDI File (type 0, 1 or 2), converting ticks to seconds with its tempo map.
+pub fn read(path: &str) -> Result<SmfFile, Box<dyn error::Error>> {
+    let data = std::fs::read(path).map_err(|err| format!("Can not read {}: {}", path, err))?;
+    let mut reader = ByteReader { data: &data, pos: 0 };
+    if reader.bytes(4)? != b"MThd" {
+        return Err(format!("{} is not a Standard MIDI File", path).into());
+    }
+    let header_len = reader.u32()? as usize;
+    let header = reader.bytes(header_len)?;
+    if header.len() < 6 {
+        return Err("Invalid MIDI file header".into());
+    }
+    let division = u16::from_be_bytes([header[4], header[5]]);
+
+    let mut track_names = Vec::new();
+    let mut events = Vec::new();
+    while !reader.at_end() {
+        let kind = reader.bytes(4)?;
+        let len = reader.u32()? as usize;
+        let chunk = reader.bytes(len)?;
+        if kind != b"MTrk" {
+            continue; // Unknown chunks must be ignored
+        }
+        let track = track_names.len();
+        track_names.push(format!("Track {}", track));
+        for (tick, event) in read_track(chunk)? {
+            if event.len() > 2 && event[0] == 0xFF && event[1] == META_TRACK_NAME {
+                track_names[track] = String::from_utf8_lossy(&event[2..]).trim().to_string();
+            }
+            events.push((tick, track, event));
+        }
+    }
+    // Stable, so events at the same tick keep the track and file order.
+    events.sort_by_key(|(tick, _, _)| *tick);
+
+    // SMPTE division has negative frames per second at the high byte and ticks per frame at the low.
+    let smpte_ticks_per_second = if division & 0x8000 != 0 {
+        let fps = -((division >> 8) as u8 as i8) as f64;
+        let fps = if fps == 29.0 { 29.97 } else { fps };
+        Some(fps * (division & 0xFF) as f64)
+    } else {
+        None
+    };
+    let ticks_per_quarter = (division & 0x7FFF).max(1) as f64;
+    let mut usec_per_quarter = 60_000_000.0 / DEFAULT_BPM;
+    let mut last_tick = 0;
+    let mut time = 0.0;
+    let mut smf_events = Vec::new();
+    for (tick, track, data) in events {
+        time += match smpte_ticks_per_second {
+            Some(ticks_per_second) => (tick - last_tick) as f64 / ticks_per_second,
+            None => (tick - last_tick) as f64 / ticks_per_quarter * usec_per_quarter / 1_000_000.0,
+        };
+        last_tick = tick;
+        match data.first() {
+            // An empty F7 escape carries nothing to show.
+            None => continue,
+            Some(0xFF) => {
+                // Meta events are FF, kind and the data, without the length.
+                if data.len() == 5 && data[1] == META_TEMPO {
+                    usec_per_quarter = u32::from_be_bytes([0, data[2], data[3], data[4]]) as f64;
+                }
+                continue;
+            }
+            _ => {}
+        }
+        smf_events.push(SmfEvent { time, track, data });
+    }
+    Ok(SmfFile { track_names, events: smf_events })
+}
+
+/// ALSA event for a channel message or sysex, to go through the same decoding as live events.
+pub fn to_seq_event(data: &[u8]) -> Option<seq::Event<'static>> {
+    let status = *data.first()?;
+    if status == 0xF0 || status == 0xF7 {
+        return Some(seq::Event::new_ext(seq::EventType::Sysex, data.to_vec()));
+    }
+    let channel = status & 0x0F;
+    let data1 = *data.get(1)?;
+    let note = |kind, velocity| {
+        seq::Event::new(kind, &seq::EvNote { channel, note: data1, velocity, off_velocity: 0, duration: 0 })
+    };
+    let ctrl = |kind, param, value| seq::Event::new(kind, &seq::EvCtrl { channel, param, value });
+    let event = match status & 0xF0 {
+        0x80 => note(seq::EventType::Noteoff, *data.get(2)?),
+        0x90 => note(seq::EventType::Noteon, *data.get(2)?),
+        0xA0 => note(seq::EventType::Keypress, *data.get(2)?),
+        0xB0 => ctrl(seq::EventType::Controller, data1 as u32, *data.get(2)? as i32),
+        0xC0 => ctrl(seq::EventType::Pgmchange, 0, data1 as i32),
+        0xD0 => ctrl(seq::EventType::Chanpress, 0, data1 as i32),
+        0xE0 => ctrl(seq::EventType::Pitchbend, 0, ((*data.get(2)? as i32) << 7 | data1 as i32) - 8192),
+        _ => return None,
+    };
+    Some(event)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir().join(format!("{}-{}.mid", name, std::process::id())).to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn write_read_round_trip() {
+        let path = temp_path("round-trip");
+        let source = seq::Addr { client: 20, port: 0 };
+        let messages: &[(f64, &[u8])] = &[
+            (0.0, &[0x90, 60, 100]),
+            (0.5, &[0xB1, 7, 90]),
+            (1.0, &[0x80, 60, 0]),
+            (1.5, &[0xE2, 0x00, 0x50]),
+        ];
+        {
+            let mut recorder = SmfRecorder::create(&path, (4, 4)).unwrap();
+            for (time, data) in messages {
+                recorder.record(*time, source, "Keyboard", &to_seq_event(data).unwrap());
+            }
+            // Saved on drop
+        }
+        let smf = read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(smf.track_names, vec!["Terminal MIDI Monitor", "Keyboard"]);
+        assert_eq!(smf.events.len(), messages.len());
+        for (event, (time, data)) in smf.events.iter().zip(messages) {
+            assert!((event.time - time).abs() < 0.001, "{} != {}", event.time, time);
+            assert_eq!(&event.data[..], *data);
+        }
+    }
+
+    #[test]
+    fn tempo_changes_keep_the_times() {
+        let path = temp_path("tempo");
+        let source = seq::Addr { client: 20, port: 0 };
+        let mut recorder = SmfRecorder::create(&path, (3, 4)).unwrap();
+        recorder.tempo(1.0, 90.0);
+        recorder.record(2.0, source, "Keyboard", &to_seq_event(&[0x90, 64, 80]).unwrap());
+        recorder.save().unwrap();
+        let smf = read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(smf.events.len(), 1);
+        assert!((smf.events[0].time - 2.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn empty_recording_is_a_valid_file() {
+        let path = temp_path("empty");
+        drop(SmfRecorder::create(&path, (4, 4)).unwrap());
+        let smf = read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(smf.events.is_empty());
+    }
+
+    #[test]
+    fn empty_escape_is_skipped() {
+        let path = temp_path("empty-escape");
+        let track = [0x00, 0xF7, 0x00, 0x00, 0x90, 0x40, 0x50, 0x00, 0xFF, 0x2F, 0x00];
+        let mut file = b"MThd\x00\x00\x00\x06\x00\x00\x00\x01\x00\x60MTrk".to_vec();
+        file.extend_from_slice(&(track.len() as u32).to_be_bytes());
+        file.extend_from_slice(&track);
+        std::fs::write(&path, file).unwrap();
+        let smf = read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(smf.events.len(), 1);
+        assert_eq!(smf.events[0].data, vec![0x90, 0x40, 0x50]);
+    }
+}
@@ -0,0 +1,285 @@
+/**
+ *  Terminal MIDI Monitor -- Shows MIDI Events on the terminal
+ *  Copyright (C) 2019 David Moreno / Coralbits SL <dmoreno@coralbits.com>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use std::collections::HashMap;
+
+lazy_static! {
+    static ref MANUFACTURER_MAP: HashMap<u32, String> = build_manufacturer_map();
+}
+
+pub const SYSEX_START: u8 = 0xF0;
+pub const SYSEX_END: u8 = 0xF7;
+const UNIVERSAL_NON_REAL_TIME: u8 = 0x7E;
+const UNIVERSAL_REAL_TIME: u8 = 0x7F;
+
+// Bytes shown per hex dump line
+const HEX_DUMP_WIDTH: usize = 16;
+
+// List from https://www.midi.org/specifications-old/item/manufacturer-id-numbers
+//
+// All IDs are stored as 3 bytes. One byte IDs are in the high byte (0x41 -> 0x410000),
+// three byte IDs (that always start with 0x00) keep their two extra bytes in the
+// low bytes (00 20 29 -> 0x002029), so both kinds never collide.
+fn build_manufacturer_map() -> HashMap<u32, String> {
+    [
+        (0x01_0000, "Sequential Circuits".to_string()),
+        (0x02_0000, "Big Briar".to_string()),
+        (0x03_0000, "Octave / Plateau".to_string()),
+        (0x04_0000, "Moog".to_string()),
+        (0x05_0000, "Passport Designs".to_string()),
+        (0x06_0000, "Lexicon".to_string()),
+        (0x07_0000, "Kurzweil".to_string()),
+        (0x08_0000, "Fender".to_string()),
+        (0x09_0000, "Gulbransen".to_string()),
+        (0x0A_0000, "AKG Acoustics".to_string()),
+        (0x0B_0000, "Voyce Music".to_string()),
+        (0x0C_0000, "Waveframe".to_string()),
+        (0x0D_0000, "ADA".to_string()),
+        (0x0E_0000, "Garfield Electronics".to_string()),
+        (0x0F_0000, "Ensoniq".to_string()),
+        (0x10_0000, "Oberheim".to_string()),
+        (0x11_0000, "Apple".to_string()),
+        (0x12_0000, "Grey Matter Response".to_string()),
+        (0x13_0000, "Digidesign".to_string()),
+        (0x14_0000, "Palmtree Instruments".to_string()),
+        (0x15_0000, "JLCooper Electronics".to_string()),
+        (0x16_0000, "Lowrey".to_string()),
+        (0x17_0000, "Adams-Smith".to_string()),
+        (0x18_0000, "E-mu".to_string()),
+        (0x19_0000, "Harmony Systems".to_string()),
+        (0x1A_0000, "ART".to_string()),
+        (0x1B_0000, "Baldwin".to_string()),
+        (0x1C_0000, "Eventide".to_string()),
+        (0x1D_0000, "Inventronics".to_string()),
+        (0x1F_0000, "Clarity".to_string()),
+        (0x20_0000, "Passac".to_string()),
+        (0x21_0000, "SIEL".to_string()),
+        (0x22_0000, "Synthaxe".to_string()),
+        (0x24_0000, "Hohner".to_string()),
+        (0x25_0000, "Twister".to_string()),
+        (0x26_0000, "Solton".to_string()),
+        (0x27_0000, "Jellinghaus MS".to_string()),
+        (0x28_0000, "Southworth Music Systems".to_string()),
+        (0x29_0000, "PPG".to_string()),
+        (0x2A_0000, "JEN".to_string()),
+        (0x2B_0000, "Solid State Logic".to_string()),
+        (0x2C_0000, "Audio Veritrieb".to_string()),
+        (0x2F_0000, "Elka".to_string()),
+        (0x30_0000, "Dynacord".to_string()),
+        (0x31_0000, "Viscount".to_string()),
+        (0x33_0000, "Clavia".to_string()),
+        (0x36_0000, "Cheetah".to_string()),
+        (0x39_0000, "Soundcraft".to_string()),
+        (0x3E_0000, "Waldorf".to_string()),
+        (0x3F_0000, "Quasimidi".to_string()),
+        (0x40_0000, "Kawai".to_string()),
+        (0x41_0000, "Roland".to_string()),
+        (0x42_0000, "Korg".to_string()),
+        (0x43_0000, "Yamaha".to_string()),
+        (0x44_0000, "Casio".to_string()),
+        (0x46_0000, "Kamiya Studio".to_string()),
+        (0x47_0000, "Akai".to_string()),
+        (0x48_0000, "Victor (JVC)".to_string()),
+        (0x4B_0000, "Fujitsu".to_string()),
+        (0x4C_0000, "Sony".to_string()),
+        (0x4E_0000, "Teac".to_string()),
+        (0x50_0000, "Matsushita".to_string()),
+        (0x51_0000, "Fostex".to_string()),
+        (0x52_0000, "Zoom".to_string()),
+        (0x54_0000, "Matsushita Communication".to_string()),
+        (0x55_0000, "Suzuki".to_string()),
+        (0x56_0000, "Fuji Sound".to_string()),
+        (0x57_0000, "Acoustic Technical Laboratory".to_string()),
+        (0x7D_0000, "Non-Commercial".to_string()),
+        (0x7E_0000, "Universal Non-Real Time".to_string()),
+        (0x7F_0000, "Universal Real Time".to_string()),
+        (0x00_000E, "Alesis".to_string()),
+        (0x00_003B, "Mark Of The Unicorn (MOTU)".to_string()),
+        (0x00_0041, "Microsoft".to_string()),
+        (0x00_0066, "Mackie Designs".to_string()),
+        (0x00_0105, "M-Audio (Midiman)".to_string()),
+        (0x00_010C, "Line 6".to_string()),
+        (0x00_201F, "TC Electronic".to_string()),
+        (0x00_2029, "Focusrite / Novation".to_string()),
+        (0x00_2032, "Behringer".to_string()),
+        (0x00_2033, "Access Music".to_string()),
+        (0x00_203C, "Elektron".to_string()),
+        (0x00_206B, "Arturia".to_string()),
+        (0x00_2076, "Teenage Engineering".to_string()),
+        (0x00_2109, "Native Instruments".to_string()),
+    ].iter().cloned().collect()
+}
+
+fn universal_non_real_time_name(sub_id1: u8, sub_id2: u8) -> String {
+    match (sub_id1, sub_id2) {
+        (0x01, _) => "Sample Dump Header".to_string(),
+        (0x02, _) => "Sample Data Packet".to_string(),
+        (0x03, _) => "Sample Dump Request".to_string(),
+        (0x04, _) => "MIDI Time Code Cueing".to_string(),
+        (0x05, _) => "Sample Dump Extensions".to_string(),
+        (0x06, 0x01) => "General Information: Identity Request".to_string(),
+        (0x06, 0x02) => "General Information: Identity Reply".to_string(),
+        (0x06, _) => "General Information".to_string(),
+        (0x07, _) => "File Dump".to_string(),
+        (0x08, _) => "MIDI Tuning Standard".to_string(),
+        (0x09, 0x01) => "General MIDI 1 System On".to_string(),
+        (0x09, 0x02) => "General MIDI System Off".to_string(),
+        (0x09, 0x03) => "General MIDI 2 System On".to_string(),
+        (0x09, _) => "General MIDI".to_string(),
+        (0x0A, _) => "Downloadable Sounds".to_string(),
+        (0x0B, _) => "File Reference".to_string(),
+        (0x0C, _) => "MIDI Visual Control".to_string(),
+        (0x0D, _) => "MIDI Capability Inquiry".to_string(),
+        (0x7B, _) => "End Of File".to_string(),
+        (0x7C, _) => "Wait".to_string(),
+        (0x7D, _) => "Cancel".to_string(),
+        (0x7E, _) => "NAK".to_string(),
+        (0x7F, _) => "ACK".to_string(),
+        _ => format!("Unknown Sub-ID {:02X} {:02X}", sub_id1, sub_id2),
+    }
+}
+
+fn universal_real_time_name(sub_id1: u8, sub_id2: u8) -> String {
+    match (sub_id1, sub_id2) {
+        (0x01, 0x01) => "MIDI Time Code: Full Message".to_string(),
+        (0x01, 0x02) => "MIDI Time Code: User Bits".to_string(),
+        (0x01, _) => "MIDI Time Code".to_string(),
+        (0x02, _) => "MIDI Show Control".to_string(),
+        (0x03, 0x01) => "Notation: Bar Number".to_string(),
+        (0x03, 0x02) => "Notation: Time Signature (Immediate)".to_string(),
+        (0x03, 0x42) => "Notation: Time Signature (Delayed)".to_string(),
+        (0x03, _) => "Notation Information".to_string(),
+        (0x04, 0x01) => "Device Control: Master Volume".to_string(),
+        (0x04, 0x02) => "Device Control: Master Balance".to_string(),
+        (0x04, 0x03) => "Device Control: Master Fine Tuning".to_string(),
+        (0x04, 0x04) => "Device Control: Master Coarse Tuning".to_string(),
+        (0x04, 0x05) => "Device Control: Global Parameter Control".to_string(),
+        (0x04, _) => "Device Control".to_string(),
+        (0x05, _) => "Real Time MTC Cueing".to_string(),
+        (0x06, _) => "MIDI Machine Control Command".to_string(),
+        (0x07, _) => "MIDI Machine Control Response".to_string(),
+        (0x08, _) => "MIDI Tuning Standard".to_string(),
+        (0x09, _) => "Controller Destination Setting".to_string(),
+        (0x0A, _) => "Key-based Instrument Control".to_string(),
+        (0x0B, _) => "Scalable Polyphony MIP".to_string(),
+        (0x0C, _) => "Mobile Phone Control".to_string(),
+        _ => format!("Unknown Sub-ID {:02X} {:02X}", sub_id1, sub_id2),
+    }
+}
+
+/// Returns the manufacturer ID of a full sysex message (starting with F0), and how
+/// many bytes it uses (1 or 3).
+fn manufacturer_id(data: &[u8]) -> Option<(u32, usize)> {
+    match data.get(1) {
+        Some(0x00) => {
+            if data.len() < 4 {
+                return None;
+            }
+            Some(((data[2] as u32) << 8 | data[3] as u32, 3))
+        }
+        Some(id) => Some(((*id as u32) << 16, 1)),
+        None => None,
+    }
+}
+
+fn manufacturer_id_to_string(id: u32, len: usize) -> String {
+    if len == 1 {
+        format!("{:02X}", id >> 16)
+    } else {
+        format!("00 {:02X} {:02X}", (id >> 8) & 0xFF, id & 0xFF)
+    }
+}
+
+/// Human description of a sysex message: manufacturer and, for universal messages,
+/// the device ID and sub-IDs.
+pub fn describe(data: &[u8]) -> String {
+    if data.first() != Some(&SYSEX_START) {
+        return "Fragment".to_string();
+    }
+    let (id, len) = match manufacturer_id(data) {
+        Some(id) => id,
+        None => return "Truncated".to_string(),
+    };
+    let manufacturer = match MANUFACTURER_MAP.get(&id) {
+        Some(name) => name.to_string(),
+        None => "Unknown manufacturer".to_string(),
+    };
+    let header = format!("{} ({})", manufacturer, manufacturer_id_to_string(id, len));
+
+    let universal = data[1];
+    if universal != UNIVERSAL_NON_REAL_TIME && universal != UNIVERSAL_REAL_TIME {
+        return header;
+    }
+    // F0 7E/7F <device id> <sub id 1> <sub id 2> ...
+    if data.len() < 5 {
+        return header;
+    }
+    let device = match data[2] {
+        0x7F => "All".to_string(),
+        device => format!("{:02X}", device),
+    };
+    let sub_id2 = data.get(4).cloned().unwrap_or(0);
+    let name = if universal == UNIVERSAL_NON_REAL_TIME {
+        universal_non_real_time_name(data[3], sub_id2)
+    } else {
+        universal_real_time_name(data[3], sub_id2)
+    };
+    format!("{} | Device {} | {}", header, device, name)
+}
+
+/// Hex dump of the message, HEX_DUMP_WIDTH bytes per line, each line prefixed by
+/// `indent` and the offset of its first byte.
+pub fn hex_dump(data: &[u8], indent: &str) -> String {
+    data.chunks(HEX_DUMP_WIDTH)
+        .enumerate()
+        .map(|(n, chunk)| {
+            let bytes: Vec<String> = chunk.iter().map(|b| format!("{:02X}", b)).collect();
+            format!("{}{:04X}: {}", indent, n * HEX_DUMP_WIDTH, bytes.join(" "))
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manufacturers() {
+        assert_eq!(describe(&[0xF0, 0x41, 0x10, 0x42, 0xF7]), "Roland (41)");
+        assert_eq!(describe(&[0xF0, 0x00, 0x20, 0x29, 0x02, 0xF7]), "Focusrite / Novation (00 20 29)");
+        assert_eq!(describe(&[0xF0, 0x00, 0x20]), "Truncated");
+        assert_eq!(describe(&[0x10, 0x20, 0xF7]), "Fragment");
+    }
+
+    #[test]
+    fn universal() {
+        assert_eq!(
+            describe(&[0xF0, 0x7E, 0x7F, 0x09, 0x01, 0xF7]),
+            "Universal Non-Real Time (7E) | Device All | General MIDI 1 System On"
+        );
+    }
+
+    #[test]
+    fn hex_dump_lines() {
+        let data: Vec<u8> = (0..20).collect();
+        let dump = hex_dump(&data, "  ");
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1], "  0010: 10 11 12 13");
+    }
+}
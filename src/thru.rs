@@ -0,0 +1,277 @@
+/**
+ *  Terminal MIDI Monitor -- Shows MIDI Events on the terminal
+ *  Copyright (C) 2019 David Moreno / Coralbits SL <dmoreno@coralbits.com>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use alsa::seq;
+use std::collections::HashSet;
+use std::error;
+use std::ffi::CString;
+use crate::filter;
+
+// Applied to the velocity of the forwarded Note ON.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum VelocityCurve {
+    Linear,
+    Fixed(u8),
+    Scale(f64),
+    Gamma(f64), // < 1 louder, > 1 softer
+}
+
+impl VelocityCurve {
+    fn parse(curve: &str) -> Result<VelocityCurve, Box<dyn error::Error>> {
+        let invalid = || format!("Invalid velocity curve {}. Use fixed:N, scale:F or gamma:G", curve);
+        let (kind, value) = match curve.find(':') {
+            Some(colon) => (&curve[..colon], &curve[colon + 1..]),
+            None => return Err(invalid().into()),
+        };
+        let value: f64 = value.parse().map_err(|_| invalid())?;
+        match kind {
+            "fixed" if (1.0..=127.0).contains(&value) => Ok(VelocityCurve::Fixed(value as u8)),
+            "scale" if value > 0.0 => Ok(VelocityCurve::Scale(value)),
+            "gamma" if value > 0.0 => Ok(VelocityCurve::Gamma(value)),
+            _ => Err(invalid().into()),
+        }
+    }
+
+    // Velocity 0 is a Note OFF, and stays so.
+    fn apply(self, velocity: u8) -> u8 {
+        if velocity == 0 {
+            return 0;
+        }
+        let velocity = match self {
+            VelocityCurve::Linear => return velocity,
+            VelocityCurve::Fixed(fixed) => return fixed,
+            VelocityCurve::Scale(scale) => velocity as f64 * scale,
+            VelocityCurve::Gamma(gamma) => 127.0 * (velocity as f64 / 127.0).powf(gamma),
+        };
+        velocity.round().clamp(1.0, 127.0) as u8
+    }
+}
+
+// Type name of the raw events, as the decoded ones, for --thru-drop. None for the events
+// that are not MIDI, as the port announcements, which are never forwarded.
+fn raw_type_name(kind: seq::EventType) -> Option<&'static str> {
+    let name = match kind {
+        seq::EventType::Noteon => "noteon",
+        seq::EventType::Noteoff => "noteoff",
+        seq::EventType::Keypress => "polyaftertouch",
+        seq::EventType::Controller => "cc",
+        seq::EventType::Control14 => "cc14",
+        seq::EventType::Regparam => "rpn",
+        seq::EventType::Nonregparam => "nrpn",
+        seq::EventType::Pgmchange => "program",
+        seq::EventType::Chanpress => "chanpress",
+        seq::EventType::Pitchbend => "pitchbend",
+        seq::EventType::Sysex => "sysex",
+        seq::EventType::Qframe => "mtc",
+        seq::EventType::Clock => "clock",
+        seq::EventType::Songpos => "songpos",
+        seq::EventType::Songsel => "songsel",
+        seq::EventType::Start => "start",
+        seq::EventType::Stop => "stop",
+        seq::EventType::Continue => "continue",
+        seq::EventType::TuneRequest => "tunerequest",
+        seq::EventType::Reset => "reset",
+        seq::EventType::Sensing => "sensing",
+        _ => return None,
+    };
+    Some(name)
+}
+
+fn is_channel_ctrl(kind: seq::EventType) -> bool {
+    matches!(
+        kind,
+        seq::EventType::Controller
+            | seq::EventType::Control14
+            | seq::EventType::Regparam
+            | seq::EventType::Nonregparam
+            | seq::EventType::Pgmchange
+            | seq::EventType::Chanpress
+            | seq::EventType::Pitchbend
+    )
+}
+
+// FROM:TO, 1 based. FROM may be "all".
+fn parse_channel_map(maps: &[&str]) -> Result<[u8; 16], Box<dyn error::Error>> {
+    let mut channels = [0u8; 16];
+    for (channel, to) in channels.iter_mut().enumerate() {
+        *to = channel as u8;
+    }
+    let parse_channel = |channel: &str| match channel.trim().parse::<u8>() {
+        Ok(channel) if (1..=16).contains(&channel) => Ok(channel - 1),
+        _ => Err(format!("Invalid channel {}, must be 1 to 16", channel)),
+    };
+    for map in maps {
+        let (from, to) = match map.find(':') {
+            Some(colon) => (&map[..colon], parse_channel(&map[colon + 1..])?),
+            None => return Err(format!("Invalid channel map {}. Use FROM:TO, as 1:10 or all:2", map).into()),
+        };
+        if from.trim() == "all" {
+            channels = [to; 16];
+        } else {
+            channels[parse_channel(from)? as usize] = to;
+        }
+    }
+    Ok(channels)
+}
+
+/// Output port that forwards every received event, with optional transforms.
+pub struct Thru {
+    port: i32,
+    channels: [u8; 16], // Output channel per input channel
+    transpose: i32,
+    velocity: VelocityCurve,
+    drop: HashSet<&'static str>,
+}
+
+impl Thru {
+    pub fn new(
+        channel_maps: &[&str],
+        transpose: Option<&str>,
+        velocity: Option<&str>,
+        drop: Option<&str>,
+    ) -> Result<Thru, Box<dyn error::Error>> {
+        let transpose = match transpose {
+            Some(transpose) => transpose.parse::<i32>().map_err(|_| format!("Invalid transpose {}", transpose))?,
+            None => 0,
+        };
+        Ok(Thru {
+            port: 0,
+            channels: parse_channel_map(channel_maps)?,
+            transpose,
+            velocity: velocity.map(VelocityCurve::parse).transpose()?.unwrap_or(VelocityCurve::Linear),
+            drop: drop.map(filter::parse_types).transpose()?.unwrap_or_default(),
+        })
+    }
+
+    pub fn create_port(&mut self, seq: &seq::Seq) -> Result<(), Box<dyn error::Error>> {
+        let mut pinfo = seq::PortInfo::empty()?;
+        pinfo.set_capability(seq::READ | seq::SUBS_READ);
+        pinfo.set_type(seq::MIDI_GENERIC | seq::APPLICATION);
+        pinfo.set_name(&CString::new("Thru")?);
+        seq.create_port(&pinfo)?;
+        self.port = pinfo.get_port();
+        Ok(())
+    }
+
+    fn channel(&self, channel: u8) -> u8 {
+        self.channels[channel as usize & 0x0F]
+    }
+
+    // The event to forward, or None to drop it.
+    fn transform(&self, ev: &seq::Event) -> Option<seq::Event<'static>> {
+        let kind = ev.get_type();
+        if self.drop.contains(raw_type_name(kind)?) {
+            return None;
+        }
+        if let Some(mut note) = ev.get_data::<seq::EvNote>() {
+            let transposed = note.note as i32 + self.transpose;
+            if !(0..=127).contains(&transposed) {
+                return None;
+            }
+            note.note = transposed as u8;
+            note.channel = self.channel(note.channel);
+            if kind == seq::EventType::Noteon {
+                note.velocity = self.velocity.apply(note.velocity);
+            }
+            return Some(seq::Event::new(kind, &note));
+        }
+        if let Some(mut ctrl) = ev.get_data::<seq::EvCtrl>() {
+            if is_channel_ctrl(kind) {
+                ctrl.channel = self.channel(ctrl.channel);
+            }
+            return Some(seq::Event::new(kind, &ctrl));
+        }
+        if let Some(queue) = ev.get_data::<seq::EvQueueControl<()>>() {
+            return Some(seq::Event::new(kind, &queue));
+        }
+        if let Some(()) = ev.get_data::<()>() {
+            return Some(seq::Event::new(kind, &()));
+        }
+        ev.get_ext().map(|data| seq::Event::new_ext(kind, data.to_vec()))
+    }
+
+    /// Sends the event to the subscribers of the thru port. Never the events of our own ports, that would loop.
+    pub fn forward(&self, seq: &seq::Seq, ev: &seq::Event) -> Result<(), Box<dyn error::Error>> {
+        if ev.get_source().client == seq.client_id()? {
+            return Ok(());
+        }
+        if let Some(mut out) = self.transform(ev) {
+            out.set_source(self.port);
+            out.set_subs();
+            out.set_direct();
+            seq.event_output_direct(&mut out)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note_on(channel: u8, note: u8, velocity: u8) -> seq::Event<'static> {
+        seq::Event::new(seq::EventType::Noteon, &seq::EvNote { channel, note, velocity, off_velocity: 0, duration: 0 })
+    }
+
+    fn note_of(ev: &seq::Event) -> (u8, u8, u8) {
+        let note: seq::EvNote = ev.get_data().unwrap();
+        (note.channel, note.note, note.velocity)
+    }
+
+    #[test]
+    fn channel_map_and_transpose() {
+        let thru = Thru::new(&["1:10", "2:3"], Some("-12"), None, None).unwrap();
+        assert_eq!(note_of(&thru.transform(&note_on(0, 60, 100)).unwrap()), (9, 48, 100));
+        assert_eq!(note_of(&thru.transform(&note_on(1, 60, 100)).unwrap()), (2, 48, 100));
+        assert_eq!(note_of(&thru.transform(&note_on(4, 60, 100)).unwrap()), (4, 48, 100));
+        // Out of range after transposing
+        assert!(thru.transform(&note_on(0, 5, 100)).is_none());
+        let thru = Thru::new(&["all:16"], None, None, None).unwrap();
+        let cc = seq::Event::new(seq::EventType::Controller, &seq::EvCtrl { channel: 3, param: 7, value: 90 });
+        let out: seq::EvCtrl = thru.transform(&cc).unwrap().get_data().unwrap();
+        assert_eq!((out.channel, out.param, out.value), (15, 7, 90));
+    }
+
+    #[test]
+    fn velocity_curves() {
+        assert_eq!(VelocityCurve::parse("fixed:100").unwrap().apply(20), 100);
+        assert_eq!(VelocityCurve::parse("scale:2").unwrap().apply(100), 127);
+        assert_eq!(VelocityCurve::parse("scale:0.5").unwrap().apply(1), 1);
+        assert_eq!(VelocityCurve::parse("gamma:0.5").unwrap().apply(32), 64);
+        // Note OFF stays a Note OFF
+        assert_eq!(VelocityCurve::parse("fixed:100").unwrap().apply(0), 0);
+        assert!(VelocityCurve::parse("fixed:0").is_err());
+        assert!(VelocityCurve::parse("loud").is_err());
+    }
+
+    #[test]
+    fn drop_types() {
+        let thru = Thru::new(&[], None, None, Some("note,clock")).unwrap();
+        assert!(thru.transform(&note_on(0, 60, 100)).is_none());
+        assert!(thru.transform(&seq::Event::new(seq::EventType::Clock, &seq::EvQueueControl { queue: 0, value: () })).is_none());
+        let program = seq::Event::new(seq::EventType::Pgmchange, &seq::EvCtrl { channel: 0, param: 0, value: 5 });
+        assert!(thru.transform(&program).is_some());
+    }
+
+    #[test]
+    fn invalid_options() {
+        assert!(Thru::new(&["17:1"], None, None, None).is_err());
+        assert!(Thru::new(&["1-2"], None, None, None).is_err());
+        assert!(Thru::new(&[], Some("up"), None, None).is_err());
+        assert!(Thru::new(&[], None, None, Some("nope")).is_err());
+    }
+}
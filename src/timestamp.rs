@@ -0,0 +1,193 @@
+/**
+ *  Terminal MIDI Monitor -- Shows MIDI Events on the terminal
+ *  Copyright (C) 2019 David Moreno / Coralbits SL <dmoreno@coralbits.com>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use alsa::seq;
+use std::collections::HashMap;
+use std::error;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::event::{DecodedEvent, MidiEvent};
+use crate::mtc;
+
+/// What the first column shows.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TimeMode {
+    Absolute, // Seconds since start
+    Delta, // Seconds since the previous event
+    SourceDelta, // Seconds since the previous event of the same source
+    Wall, // Local time of day
+    BarsBeatsTicks, // Song position from the clock
+    Smpte, // Last MIDI Time Code
+}
+
+impl TimeMode {
+    pub fn from_name(name: &str) -> Result<TimeMode, Box<dyn error::Error>> {
+        match name {
+            "absolute" => Ok(TimeMode::Absolute),
+            "delta" => Ok(TimeMode::Delta),
+            "source-delta" => Ok(TimeMode::SourceDelta),
+            "wall" => Ok(TimeMode::Wall),
+            "bbt" => Ok(TimeMode::BarsBeatsTicks),
+            "smpte" => Ok(TimeMode::Smpte),
+            _ => Err(format!("Unknown time mode {}", name).into()),
+        }
+    }
+}
+
+/// Formats the time column, keeping what the relative modes need.
+pub struct TimeColumn {
+    mode: TimeMode,
+    start: SystemTime, // Wall clock at time 0
+    last: f64,
+    last_per_source: HashMap<seq::Addr, f64>,
+    bars_beats_ticks: Option<String>,
+    timecode: Option<mtc::Timecode>,
+}
+
+// Local time of day, as HH:MM:SS.uuuuuu
+fn local_time(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs() as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe {
+        libc::localtime_r(&seconds, &mut tm);
+    }
+    format!("{:02}:{:02}:{:02}.{:06}", tm.tm_hour, tm.tm_min, tm.tm_sec, since_epoch.subsec_micros())
+}
+
+// Clock and running MTC are not log lines, so they do not reset the deltas.
+fn is_log_line(event: &MidiEvent) -> bool {
+    !matches!(event, MidiEvent::Clock { .. } | MidiEvent::Mtc { .. })
+}
+
+impl TimeColumn {
+    pub fn new(mode: TimeMode, start: SystemTime) -> TimeColumn {
+        TimeColumn {
+            mode,
+            start,
+            last: 0.0,
+            last_per_source: HashMap::new(),
+            bars_beats_ticks: None,
+            timecode: None,
+        }
+    }
+
+    /// Follows the clock and MTC position. For every event, even the filtered out.
+    pub fn observe(&mut self, ev: &DecodedEvent) {
+        match &ev.event {
+            MidiEvent::Clock { bars_beats_ticks, .. } | MidiEvent::SongPosition { bars_beats_ticks, .. } => {
+                self.bars_beats_ticks = Some(bars_beats_ticks.clone());
+            }
+            MidiEvent::Mtc { timecode }
+            | MidiEvent::MtcFullFrame { timecode }
+            | MidiEvent::MtcDropped { timecode, .. }
+            | MidiEvent::MtcBackwards { timecode } => self.timecode = Some(*timecode),
+            _ => {}
+        }
+    }
+
+    /// The column for a shown event. With the processing delay if known.
+    pub fn format(&mut self, ev: &DecodedEvent) -> String {
+        let time = match self.mode {
+            TimeMode::Absolute => format!("{:10.3}", ev.time),
+            TimeMode::Delta => {
+                let delta = ev.time - self.last;
+                if is_log_line(&ev.event) {
+                    self.last = ev.time;
+                }
+                format!("{:>+10.6}", delta)
+            }
+            TimeMode::SourceDelta => {
+                let last = self.last_per_source.get(&ev.source).cloned().unwrap_or(0.0);
+                if is_log_line(&ev.event) {
+                    self.last_per_source.insert(ev.source, ev.time);
+                }
+                format!("{:>+10.6}", ev.time - last)
+            }
+            TimeMode::Wall => local_time(self.start + std::time::Duration::from_secs_f64(ev.time.max(0.0))),
+            TimeMode::BarsBeatsTicks => format!("{:>10}", self.bars_beats_ticks.as_deref().unwrap_or("No clock")),
+            TimeMode::Smpte => match self.timecode {
+                Some(tc) => {
+                    let separator = if tc.rate == mtc::FrameRate::Fps2997Drop { ';' } else { ':' };
+                    format!("{:02}:{:02}:{:02}{}{:02}", tc.hours, tc.minutes, tc.seconds, separator, tc.frames)
+                }
+                None => format!("{:>11}", "No MTC"),
+            },
+        };
+        match ev.processed {
+            Some(processed) => format!("{} {:>+8.3} ms", time, (processed - ev.time) * 1000.0),
+            None => time,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(time: f64, client: i32) -> DecodedEvent {
+        DecodedEvent::for_test(time, client, MidiEvent::NoteOn { channel: 0, note: 60, velocity: 100 })
+    }
+
+    fn column(mode: TimeMode) -> TimeColumn {
+        TimeColumn::new(mode, UNIX_EPOCH)
+    }
+
+    #[test]
+    fn deltas_in_microseconds() {
+        let mut times = column(TimeMode::Delta);
+        assert_eq!(times.format(&note(1.0, 20)), " +1.000000");
+        assert_eq!(times.format(&note(1.002003, 21)), " +0.002003");
+        // Clocks do not reset the delta
+        let clock = MidiEvent::Clock { bpm: 120.0, clock_pos: 1, bars_beats_ticks: "  1:1:01".to_string(), beat: false, stats: None };
+        times.format(&DecodedEvent::for_test(1.5, 22, clock));
+        assert_eq!(times.format(&note(2.002003, 20)), " +1.000000");
+    }
+
+    #[test]
+    fn deltas_per_source() {
+        let mut times = column(TimeMode::SourceDelta);
+        times.format(&note(1.0, 20));
+        times.format(&note(1.5, 21));
+        assert_eq!(times.format(&note(1.75, 20)), " +0.750000");
+        assert_eq!(times.format(&note(1.75, 21)), " +0.250000");
+    }
+
+    #[test]
+    fn clock_and_timecode_positions() {
+        let mut bbt = column(TimeMode::BarsBeatsTicks);
+        let mut smpte = column(TimeMode::Smpte);
+        assert_eq!(bbt.format(&note(0.0, 20)), "  No clock");
+        assert_eq!(smpte.format(&note(0.0, 20)), "     No MTC");
+        let position = DecodedEvent::for_test(0.1, 20, MidiEvent::SongPosition { value: 16, bars_beats_ticks: "  2:1:00".to_string() });
+        let timecode = mtc::Timecode { hours: 1, minutes: 2, seconds: 3, frames: 4, rate: mtc::FrameRate::Fps2997Drop };
+        let mtc = DecodedEvent::for_test(0.1, 20, MidiEvent::MtcFullFrame { timecode });
+        for ev in &[position, mtc] {
+            bbt.observe(ev);
+            smpte.observe(ev);
+        }
+        assert_eq!(bbt.format(&note(0.2, 20)), "    2:1:00");
+        assert_eq!(smpte.format(&note(0.2, 20)), "01:02:03;04");
+    }
+
+    #[test]
+    fn processing_delay() {
+        let mut times = column(TimeMode::Absolute);
+        let mut ev = note(1.0, 20);
+        ev.processed = Some(1.0015);
+        assert_eq!(times.format(&ev), "     1.000   +1.500 ms");
+    }
+}
@@ -0,0 +1,557 @@
+/**
+ *  Terminal MIDI Monitor -- Shows MIDI Events on the terminal
+ *  Copyright (C) 2019 David Moreno / Coralbits SL <dmoreno@coralbits.com>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+use std::collections::VecDeque;
+use std::error;
+use std::io;
+use std::io::prelude::*;
+use crate::event::{DecodedEvent, MidiEvent};
+use crate::output;
+use crate::{hanging_notes, MidiMonitor};
+
+const MAX_ENTRIES: usize = 10_000; // Scrollback
+const HELP: &str = "q quit | space pause | arrows/PgUp/PgDn scroll | / search | n/N next/prev | c clear | f filters | h hanging notes | d dashboard | r piano roll";
+
+/// What the TUI shows above the status bar.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum View {
+    Log,
+    Dashboard, // Per channel state
+    PianoRoll,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Key {
+    Char(char),
+    Enter,
+    Escape,
+    Backspace,
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    Home,
+    End,
+}
+
+struct Entry {
+    id: u64,
+    line: String,  // As shown, with colors
+    plain: String, // Lowercase and without colors, to search
+    filtered: bool, // Filtered out by the command line filters
+    continuation: bool, // Not the first line of its event
+}
+
+/// Full screen terminal UI: the event log with scrollback, search and a status bar.
+pub struct Tui {
+    original_termios: libc::termios,
+    entries: VecDeque<Entry>,
+    next_id: u64,
+    scroll: usize, // Visible lines from the bottom. 0 follows new events.
+    paused: bool,
+    filters: bool, // Whether the command line filters apply
+    searching: bool, // Typing the search
+    search: String,
+    current_match: Option<u64>,
+    mtc: Option<String>, // Last running MTC, shown at the status bar
+    dirty: bool,
+    view: View,
+    hanging_requested: bool, // Lists the hanging notes on the next update
+}
+
+// Removes the ANSI escape sequences.
+fn strip_ansi(text: &str) -> String {
+    let mut plain = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            plain.push(c);
+        }
+    }
+    plain
+}
+
+// Cuts to `width` visible characters, keeping the escape sequences.
+fn truncate_ansi(text: &str, width: usize) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut visible = 0;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            result.push(c);
+            for c in chars.by_ref() {
+                result.push(c);
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else if visible < width {
+            result.push(c);
+            visible += 1;
+        }
+    }
+    result.push_str("\x1b[0m");
+    result
+}
+
+fn terminal_size() -> (usize, usize) {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    let ok = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0;
+    if ok && size.ws_row > 0 && size.ws_col > 0 {
+        (size.ws_row as usize, size.ws_col as usize)
+    } else {
+        (24, 80)
+    }
+}
+
+fn parse_keys(input: &[u8]) -> Vec<Key> {
+    let mut keys = Vec::new();
+    let mut pos = 0;
+    while pos < input.len() {
+        let rest = &input[pos..];
+        let (key, len) = match rest {
+            [0x1b, b'[', b'A', ..] => (Some(Key::Up), 3),
+            [0x1b, b'[', b'B', ..] => (Some(Key::Down), 3),
+            [0x1b, b'[', b'H', ..] => (Some(Key::Home), 3),
+            [0x1b, b'[', b'F', ..] => (Some(Key::End), 3),
+            [0x1b, b'[', b'1', b'~', ..] => (Some(Key::Home), 4),
+            [0x1b, b'[', b'4', b'~', ..] => (Some(Key::End), 4),
+            [0x1b, b'[', b'5', b'~', ..] => (Some(Key::PageUp), 4),
+            [0x1b, b'[', b'6', b'~', ..] => (Some(Key::PageDown), 4),
+            [0x1b, b'[', ..] => {
+                // Unknown sequence, skip up to its final byte
+                let len = rest.iter().skip(2).position(|b| (0x40..=0x7e).contains(b)).map(|p| p + 3).unwrap_or(rest.len());
+                (None, len)
+            }
+            [0x1b, ..] => (Some(Key::Escape), 1),
+            [b'\r', ..] | [b'\n', ..] => (Some(Key::Enter), 1),
+            [0x7f, ..] | [0x08, ..] => (Some(Key::Backspace), 1),
+            _ => {
+                // UTF-8 character
+                let text = String::from_utf8_lossy(rest);
+                let c = text.chars().next().unwrap_or('?');
+                let len = c.len_utf8().min(rest.len());
+                (if c.is_control() { None } else { Some(Key::Char(c)) }, len)
+            }
+        };
+        if let Some(key) = key {
+            keys.push(key);
+        }
+        pos += len.max(1);
+    }
+    keys
+}
+
+impl Tui {
+    /// Switches the terminal to raw mode and the alternate screen, until dropped.
+    pub fn new(view: View) -> Result<Tui, Box<dyn error::Error>> {
+        let mut original_termios: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original_termios) } != 0 {
+            return Err("The TUI needs a terminal".into());
+        }
+        let mut raw = original_termios;
+        // Keeps ISIG, so Control C still exits cleanly through the signal handler.
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::IEXTEN);
+        raw.c_iflag &= !(libc::IXON | libc::ICRNL);
+        raw.c_cc[libc::VMIN] = 0;
+        raw.c_cc[libc::VTIME] = 0;
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) };
+        print!("\x1b[?1049h\x1b[?25l");
+        io::stdout().flush()?;
+        Ok(Tui {
+            original_termios,
+            entries: VecDeque::new(),
+            next_id: 0,
+            scroll: 0,
+            paused: false,
+            filters: true,
+            searching: false,
+            search: String::new(),
+            current_match: None,
+            mtc: None,
+            dirty: true,
+            view,
+            hanging_requested: false,
+        })
+    }
+
+    // Each line is an entry of its own, so multi-line events as the SysEx hex dump show whole.
+    fn push_lines(&mut self, text: &str, filtered: bool) {
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim_end().to_string();
+            let plain = strip_ansi(&line).to_lowercase();
+            if self.paused && (!filtered || !self.filters) {
+                // Keep the view where it is
+                self.scroll += 1;
+            }
+            self.entries.push_back(Entry { id: self.next_id, line, plain, filtered, continuation: index > 0 });
+            self.next_id += 1;
+            if self.entries.len() > MAX_ENTRIES {
+                self.entries.pop_front();
+            }
+        }
+        self.dirty = true;
+    }
+
+    /// New decoded event. `filtered` if the command line filters would not show it.
+    pub fn push(&mut self, ev: &DecodedEvent, filtered: bool, time: &str) {
+        match &ev.event {
+            // Clock and running MTC go to the status bar, as they would flood the log.
+            // The status shows the position by beats, so only clocks on a beat change it.
+            MidiEvent::Clock { stats, beat, .. } if stats.is_none() || !beat => {
+                self.dirty |= *beat;
+                return;
+            }
+            MidiEvent::Mtc { timecode } => {
+                self.mtc = Some(timecode.to_string());
+                self.dirty = true;
+                return;
+            }
+            _ => {}
+        }
+        let (event, extra_data) = output::text_columns(&ev.event);
+        self.push_lines(&format!("{} | {:20} | {:>17} | {}", time, ev.origin, event, extra_data), filtered);
+    }
+
+    /// A message from the monitor itself, as errors.
+    pub fn message(&mut self, message: &str) {
+        self.push_lines(message, false);
+    }
+
+    fn visible(&self, entry: &Entry) -> bool {
+        !(self.filters && entry.filtered)
+    }
+
+    fn matches(&self, entry: &Entry) -> bool {
+        !self.search.is_empty() && entry.plain.contains(&self.search.to_lowercase())
+    }
+
+    // Visible lines from the bottom to the entry with this id.
+    fn position_of(&self, id: u64) -> Option<usize> {
+        self.entries.iter().rev()
+            .filter(|entry| self.visible(entry))
+            .position(|entry| entry.id == id)
+    }
+
+    fn visible_count(&self) -> usize {
+        self.entries.iter().filter(|entry| self.visible(entry)).count()
+    }
+
+    // Next match going up (older) or down (newer) from the current one, and scrolls to it.
+    fn find(&mut self, older: bool) {
+        let current = self.current_match.and_then(|id| self.position_of(id));
+        let visible: Vec<&Entry> = self.entries.iter().rev().filter(|entry| self.visible(entry)).collect();
+        let found = if older {
+            let start = current.map(|pos| pos + 1).unwrap_or(0);
+            (start..visible.len()).find(|pos| self.matches(visible[*pos]))
+        } else {
+            let end = current.unwrap_or(0);
+            (0..end).rev().find(|pos| self.matches(visible[*pos]))
+        };
+        if let Some(pos) = found {
+            self.current_match = Some(visible[pos].id);
+            self.scroll_to(pos);
+        }
+    }
+
+    // Makes the line at `pos` from the bottom visible.
+    fn scroll_to(&mut self, pos: usize) {
+        let (rows, _) = terminal_size();
+        let height = rows.saturating_sub(2).max(1);
+        if pos < self.scroll || pos >= self.scroll + height {
+            self.scroll = pos.saturating_sub(height / 2);
+        }
+        self.paused = self.scroll > 0 || self.paused;
+    }
+
+    fn scroll_by(&mut self, lines: isize) {
+        let max_scroll = self.visible_count().saturating_sub(1);
+        let scroll = (self.scroll as isize + lines).max(0) as usize;
+        self.scroll = scroll.min(max_scroll);
+        // Scrolling up stops following new events
+        if self.scroll > 0 {
+            self.paused = true;
+        }
+    }
+
+    /// Reads the pending keys. Returns true to quit.
+    pub fn read_keys(&mut self) -> Result<bool, Box<dyn error::Error>> {
+        let mut buffer = [0u8; 256];
+        let len = unsafe { libc::read(libc::STDIN_FILENO, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+        if len <= 0 {
+            return Ok(false);
+        }
+        let (rows, _) = terminal_size();
+        let page = rows.saturating_sub(3).max(1) as isize;
+        for key in parse_keys(&buffer[..len as usize]) {
+            self.dirty = true;
+            if self.searching {
+                match key {
+                    Key::Char(c) => {
+                        self.search.push(c);
+                        // Incremental, from the newest match
+                        self.current_match = None;
+                        self.find(true);
+                    }
+                    Key::Backspace => {
+                        self.search.pop();
+                        self.current_match = None;
+                        self.find(true);
+                    }
+                    Key::Enter => self.searching = false,
+                    Key::Escape => {
+                        self.searching = false;
+                        self.search.clear();
+                        self.current_match = None;
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+            match key {
+                Key::Char('q') => return Ok(true),
+                Key::Char(' ') | Key::Char('p') => {
+                    self.paused = !self.paused;
+                    if !self.paused {
+                        self.scroll = 0;
+                    }
+                }
+                Key::Up | Key::Char('k') => self.scroll_by(1),
+                Key::Down | Key::Char('j') => self.scroll_by(-1),
+                Key::PageUp => self.scroll_by(page),
+                Key::PageDown => self.scroll_by(-page),
+                Key::Home | Key::Char('g') => self.scroll_by(MAX_ENTRIES as isize),
+                Key::End | Key::Char('G') => {
+                    self.scroll = 0;
+                    self.paused = false;
+                }
+                Key::Char('/') => {
+                    self.searching = true;
+                    self.search.clear();
+                    self.current_match = None;
+                }
+                Key::Char('n') => self.find(true),
+                Key::Char('N') => self.find(false),
+                Key::Char('c') => {
+                    self.entries.clear();
+                    self.scroll = 0;
+                    self.current_match = None;
+                }
+                Key::Char('d') => {
+                    self.view = if self.view == View::Dashboard { View::Log } else { View::Dashboard };
+                }
+                Key::Char('h') => self.hanging_requested = true,
+                Key::Char('r') => {
+                    self.view = if self.view == View::PianoRoll { View::Log } else { View::PianoRoll };
+                }
+                Key::Char('f') => {
+                    self.filters = !self.filters;
+                    self.scroll = 0;
+                    self.paused = false;
+                }
+                Key::Escape => {
+                    self.search.clear();
+                    self.current_match = None;
+                }
+                _ => {}
+            }
+        }
+        Ok(false)
+    }
+
+    fn draw_log(&self, out: &mut String, height: usize, width: usize) {
+        let mut lines: Vec<&Entry> = self.entries.iter().rev()
+            .filter(|entry| self.visible(entry))
+            .skip(self.scroll)
+            .take(height)
+            .collect();
+        lines.reverse();
+        for _ in lines.len()..height {
+            out.push_str("\x1b[K\n");
+        }
+        for entry in lines {
+            if Some(entry.id) == self.current_match {
+                out.push_str(&format!("\x1b[7m{}", truncate_ansi(&strip_ansi(&entry.line), width)));
+            } else if self.matches(entry) {
+                out.push_str(&format!("\x1b[4m{}", truncate_ansi(&entry.line, width)));
+            } else {
+                out.push_str(&truncate_ansi(&entry.line, width));
+            }
+            out.push_str("\x1b[K\n");
+        }
+    }
+
+    // Other views than the log, from the top.
+    fn draw_lines(&self, out: &mut String, lines: &[String], height: usize, width: usize) {
+        for row in 0..height {
+            if let Some(line) = lines.get(row) {
+                out.push_str(&truncate_ansi(line, width));
+            }
+            out.push_str("\x1b[K\n");
+        }
+    }
+
+    // `lines` has the contents of views other than the log.
+    fn draw(&mut self, status: &str, lines: Option<Vec<String>>) -> Result<(), Box<dyn error::Error>> {
+        let (rows, width) = terminal_size();
+        let height = rows.saturating_sub(2);
+        let mut out = String::from("\x1b[H");
+        match lines {
+            Some(lines) => self.draw_lines(&mut out, &lines, height, width),
+            None => self.draw_log(&mut out, height, width),
+        }
+
+        let state = if self.paused { "PAUSED" } else { "LIVE" };
+        let filters = if self.filters { "filters on" } else { "filters off" };
+        let events = self.entries.iter().filter(|entry| !entry.continuation).count();
+        let mut status = format!(" {} | {} | {} events | {}", state, status, events, filters);
+        if let Some(mtc) = self.mtc.as_ref() {
+            status.push_str(&format!(" | MTC {}", mtc));
+        }
+        let padding = width.saturating_sub(status.chars().count());
+        status.push_str(&" ".repeat(padding));
+        out.push_str(&format!("\x1b[7m{}\x1b[K\n", truncate_ansi(&status, width)));
+
+        let bottom = if self.searching {
+            format!("/{}_", self.search)
+        } else if !self.search.is_empty() {
+            format!("Search: {} (n/N next/prev, Esc clears) | {}", self.search, HELP)
+        } else {
+            HELP.to_string()
+        };
+        out.push_str(&truncate_ansi(&bottom, width.saturating_sub(1)));
+        out.push_str("\x1b[K");
+        print!("{}", out);
+        io::stdout().flush()?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+impl Drop for Tui {
+    fn drop(&mut self) {
+        print!("\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original_termios) };
+    }
+}
+
+// BPM, clock position and connected ports.
+fn status(midi_monitor: &MidiMonitor) -> String {
+    let mut status = Vec::new();
+    if midi_monitor.last_clock > 0.0 {
+        let bpm = 60.0 / (midi_monitor.average_sec_per_clock * 24.0);
+        status.push(format!("{:.1} BPM", bpm));
+        // As the clock events, the last clock is at clock_pos - 1. Without the ticks, to redraw once per beat.
+        let bars_beats_ticks = midi_monitor.bars_beats_ticks(midi_monitor.clock_pos - 1);
+        let bars_beats = bars_beats_ticks.rsplitn(2, ':').last().unwrap_or("");
+        status.push(format!(
+            "{} {}/{}",
+            bars_beats.trim(),
+            midi_monitor.time_signature.0,
+            midi_monitor.time_signature.1
+        ));
+    } else {
+        status.push("No clock".to_string());
+    }
+    if midi_monitor.connected.is_empty() {
+        status.push("No ports connected".to_string());
+    } else {
+        let ports: Vec<&str> = midi_monitor.connected.values().map(|name| name.as_str()).collect();
+        status.push(format!("Ports: {}", ports.join(", ")));
+    }
+    status.join(" | ")
+}
+
+/// Reads the keys, if `keys_ready`, and redraws the TUI if there is something new.
+/// Returns true to quit.
+pub fn update(midi_monitor: &mut MidiMonitor, keys_ready: bool) -> Result<bool, Box<dyn error::Error>> {
+    let quit = match midi_monitor.tui.as_mut() {
+        Some(tui) if keys_ready => tui.read_keys()?,
+        Some(_) => false,
+        None => return Ok(false),
+    };
+    if midi_monitor.tui.as_ref().map(|tui| tui.hanging_requested).unwrap_or(false) {
+        let lines = hanging_notes(midi_monitor);
+        if let Some(tui) = midi_monitor.tui.as_mut() {
+            tui.hanging_requested = false;
+            if lines.is_empty() {
+                tui.message("Not tracking notes, start with --check-notes");
+            }
+            lines.iter().for_each(|line| tui.message(line));
+        }
+    }
+    let (status, lines) = match midi_monitor.tui.as_ref() {
+        Some(tui) if tui.dirty => {
+            let (rows, width) = terminal_size();
+            let lines = match tui.view {
+                View::Log => None,
+                View::Dashboard => Some(midi_monitor.dashboard.lines(width)),
+                View::PianoRoll => Some(midi_monitor.piano_roll.lines(width, rows.saturating_sub(2))),
+            };
+            (status(midi_monitor), lines)
+        }
+        _ => return Ok(quit),
+    };
+    if let Some(tui) = midi_monitor.tui.as_mut() {
+        tui.draw(&status, lines)?;
+    }
+    Ok(quit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_and_escape_sequences() {
+        assert_eq!(
+            parse_keys(b"q\x1b[A\x1b[B\x1b[5~\x1b[6~\x1b[H\x1b[4~\r\x7f"),
+            vec![
+                Key::Char('q'), Key::Up, Key::Down, Key::PageUp, Key::PageDown, Key::Home, Key::End,
+                Key::Enter, Key::Backspace,
+            ]
+        );
+        assert_eq!(parse_keys(b"\x1b"), vec![Key::Escape]);
+        assert_eq!(parse_keys("ñ/".as_bytes()), vec![Key::Char('ñ'), Key::Char('/')]);
+    }
+
+    #[test]
+    fn unknown_sequences_and_controls_are_skipped() {
+        assert_eq!(parse_keys(b"\x1b[1;5Cx\x01y"), vec![Key::Char('x'), Key::Char('y')]);
+        assert_eq!(parse_keys(b"\x1b[12"), vec![]);
+    }
+
+    #[test]
+    fn strip_colors() {
+        assert_eq!(strip_ansi("\x1b[1;31mNote On\x1b[0m | C5"), "Note On | C5");
+        assert_eq!(strip_ansi("plain"), "plain");
+    }
+
+    #[test]
+    fn truncate_counts_visible_characters() {
+        assert_eq!(truncate_ansi("\x1b[31mabcdef\x1b[0m", 3), "\x1b[31mabc\x1b[0m\x1b[0m");
+        assert_eq!(truncate_ansi("ñandú", 2), "ña\x1b[0m");
+        assert_eq!(truncate_ansi("ab", 10), "ab\x1b[0m");
+    }
+}